@@ -0,0 +1,286 @@
+use std::{fmt, path::PathBuf};
+
+use anyhow::Result;
+use clap::{Args, Command, FromArgMatches, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::fec;
+
+/// The default auxiliary prompt. It pulls the auxiliary model's predictions towards a topic that
+/// the generated text is unlikely to be about, so the two only agree on predictable tokens.
+pub const DEFAULT_AUX_PROMPT: &str = "Write only about yoga. You are absolutely obsessed with yoga. If you find yourself writing about something other than yoga, quickly change the topic back to yoga. Yoga is love, yoga is life.";
+
+/// How the steganographer's and auxiliary model's predictions are compared to decide whether a
+/// token may carry part of the message.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Metric {
+    /// The best possible accuracy of guessing which of the two distributions a token was sampled
+    /// from. Ranges from 0.5 to 1.
+    #[default]
+    Distinguishability,
+    /// The total variation distance between the two distributions. Ranges from 0 to 1.
+    TotalVariation,
+    /// The Jensen-Shannon divergence between the two distributions, in bits. Ranges from 0 to 1.
+    JensenShannon,
+}
+
+/// The secret used to encrypt the hidden message. If neither option is given, the message is not
+/// encrypted.
+#[derive(Args, Clone, Default)]
+pub struct KeyArgs {
+    /// Encrypt the hidden message with a key derived from this passphrase
+    #[arg(long, conflicts_with = "key_file")]
+    pub passphrase: Option<String>,
+
+    /// Encrypt the hidden message with a key derived from the contents of this file
+    #[arg(long)]
+    pub key_file: Option<PathBuf>,
+}
+
+impl KeyArgs {
+    /// Returns the secret to derive the key from, or `None` if encryption is disabled.
+    pub fn secret(&self) -> Result<Option<Vec<u8>>> {
+        if let Some(passphrase) = &self.passphrase {
+            Ok(Some(passphrase.as_bytes().to_vec()))
+        } else if let Some(path) = &self.key_file {
+            Ok(Some(std::fs::read(path)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl fmt::Debug for KeyArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyArgs")
+            .field(
+                "passphrase",
+                &self.passphrase.as_ref().map(|_| "<redacted>"),
+            )
+            .field("key_file", &self.key_file)
+            .finish()
+    }
+}
+
+/// Settings used to hide a message in generated text.
+#[derive(Args, Deserialize, Clone, Debug)]
+#[command(version, about)]
+#[serde(default)]
+pub struct EncodeArgs {
+    /// The user prompt for the generated text
+    ///
+    /// Tokens that are not valid UTF-8 on their own are never chosen, since the decoder could not
+    /// tell whether the text tokenizes the same way again. Many tokenizers split CJK characters
+    /// and emoji into such tokens, so the generated text avoids them, and prompts asking for text
+    /// in these scripts produce poor cover text.
+    pub prompt: String,
+
+    /// The number of tokens to skip at the start of the generation before starting to encode the
+    /// message
+    #[arg(short = 'k', long, default_value_t = 8)]
+    pub skip_start: usize,
+
+    /// The maximum number of tokens to generate
+    #[arg(short, long, default_value_t = 4096)]
+    pub token_count: usize,
+
+    /// MinP filtering value for sampling
+    #[arg(long, default_value_t = 0.02)]
+    pub min_p: f32,
+
+    /// TopK filtering value for sampling
+    #[arg(long, default_value_t = 0)]
+    pub top_k: usize,
+
+    /// Temperature sampling value
+    #[arg(long, default_value_t = 1.0)]
+    pub temp: f32,
+
+    /// The prompt given to the auxiliary model. Tokens are only used to encode the message when
+    /// the auxiliary model's predictions are similar to those of the model without a prompt.
+    #[arg(long, default_value = DEFAULT_AUX_PROMPT)]
+    pub aux_prompt: String,
+
+    /// Use the auxiliary prompt exactly as given instead of applying the model's chat template
+    #[arg(long)]
+    pub raw_aux_prompt: bool,
+
+    /// Tokens are only used to encode the message when the metric is at most this value
+    #[arg(long, default_value_t = 0.60)]
+    pub threshold: f64,
+
+    /// The metric used to compare the predictions with and without the auxiliary prompt
+    #[arg(long, value_enum, default_value_t)]
+    pub metric: Metric,
+
+    /// Trade capacity for reliability across machines, by quantizing probabilities to a coarse
+    /// grid and avoiding tokens whose decisions are close to changing
+    #[arg(long)]
+    pub robust: bool,
+
+    /// In robust mode, how close the metric, log-probabilities, and probabilities may come to a
+    /// threshold, cut-off, or quantization step before the encoder avoids the token
+    #[arg(long, default_value_t = 1e-4)]
+    pub robust_margin: f64,
+
+    /// Add this many Reed-Solomon parity shards to every 16 shards of the message, so that it can
+    /// be recovered after up to as many shards are corrupted by edits to the text. An edit also
+    /// changes the predictions for the text after it, so combine this with --robust. 0 disables
+    /// error correction
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(..=fec::MAX_PARITY as i64)
+    )]
+    pub fec: u8,
+
+    #[command(flatten)]
+    #[serde(skip)]
+    pub key: KeyArgs,
+
+    /// Save the evaluated prompts to this file, and load them from it instead of evaluating them
+    /// again on later runs with the same model, prompts and token count
+    #[arg(long)]
+    #[serde(skip)]
+    pub prompt_cache: Option<PathBuf>,
+
+    /// Write the statistics of every generated token to this file, to compare against the trace
+    /// written by `decode --trace`
+    #[arg(long)]
+    #[serde(skip)]
+    pub trace: Option<PathBuf>,
+}
+
+/// Settings used to recover a message from generated text. These must match the [`EncodeArgs`]
+/// that the text was generated with.
+#[derive(Args, Serialize, Deserialize, Clone, Debug)]
+#[command(version, about)]
+#[serde(default)]
+pub struct DecodeArgs {
+    /// The number of tokens to skip at the start of the generation before starting to encode the
+    /// message
+    #[arg(short = 'k', long, default_value_t = 8)]
+    pub skip_start: usize,
+
+    /// MinP filtering value for sampling
+    #[arg(long, default_value_t = 0.02)]
+    pub min_p: f32,
+
+    /// TopK filtering value for sampling
+    #[arg(long, default_value_t = 0)]
+    pub top_k: usize,
+
+    /// Temperature sampling value
+    #[arg(long, default_value_t = 1.0)]
+    pub temp: f32,
+
+    /// The prompt given to the auxiliary model. Tokens are only used to encode the message when
+    /// the auxiliary model's predictions are similar to those of the model without a prompt.
+    #[arg(long, default_value = DEFAULT_AUX_PROMPT)]
+    pub aux_prompt: String,
+
+    /// Use the auxiliary prompt exactly as given instead of applying the model's chat template
+    #[arg(long)]
+    pub raw_aux_prompt: bool,
+
+    /// Tokens are only used to encode the message when the metric is at most this value
+    #[arg(long, default_value_t = 0.60)]
+    pub threshold: f64,
+
+    /// The metric used to compare the predictions with and without the auxiliary prompt
+    #[arg(long, value_enum, default_value_t)]
+    pub metric: Metric,
+
+    /// Trade capacity for reliability across machines, by quantizing probabilities to a coarse
+    /// grid and avoiding tokens whose decisions are close to changing
+    #[arg(long)]
+    pub robust: bool,
+
+    /// In robust mode, how close the metric, log-probabilities, and probabilities may come to a
+    /// threshold, cut-off, or quantization step before the encoder avoids the token
+    #[arg(long, default_value_t = 1e-4)]
+    pub robust_margin: f64,
+
+    /// The number of Reed-Solomon parity shards added to every 16 shards of the message. 0 means
+    /// that the message was hidden without error correction
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(..=fec::MAX_PARITY as i64)
+    )]
+    pub fec: u8,
+
+    #[command(flatten)]
+    #[serde(skip)]
+    pub key: KeyArgs,
+
+    /// Write the statistics of every decoded token to this file, to compare against the trace
+    /// written by `encode --trace`
+    #[arg(long)]
+    #[serde(skip)]
+    pub trace: Option<PathBuf>,
+}
+
+/// Parses `T` from the given positional arguments, leaving every option at its command-line
+/// default.
+fn parse_defaults<T: Args + FromArgMatches>(positional: &[&str]) -> T {
+    let command = T::augment_args(Command::new("defaults").no_binary_name(true));
+    let mut argv = vec!["--"];
+    argv.extend_from_slice(positional);
+
+    T::from_arg_matches(&command.get_matches_from(argv))
+        .expect("Default arguments should always parse")
+}
+
+impl EncodeArgs {
+    /// Creates encoding settings for `prompt` with all other values set to their defaults.
+    pub fn new(prompt: impl Into<String>) -> Self {
+        parse_defaults(&[&prompt.into()])
+    }
+
+    /// Returns the settings needed to decode text generated with these settings. The trace file is
+    /// not carried over, so that decoding does not overwrite the encoder's trace.
+    pub fn as_decode_args(&self) -> DecodeArgs {
+        DecodeArgs {
+            skip_start: self.skip_start,
+            min_p: self.min_p,
+            top_k: self.top_k,
+            temp: self.temp,
+            aux_prompt: self.aux_prompt.clone(),
+            raw_aux_prompt: self.raw_aux_prompt,
+            threshold: self.threshold,
+            metric: self.metric,
+            robust: self.robust,
+            robust_margin: self.robust_margin,
+            fec: self.fec,
+            key: self.key.clone(),
+            trace: None,
+        }
+    }
+}
+
+impl Default for EncodeArgs {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl Default for DecodeArgs {
+    fn default() -> Self {
+        parse_defaults(&[])
+    }
+}
+
+#[test]
+fn test_default_args() {
+    let encode_args = EncodeArgs::new("--not-a-flag");
+    assert_eq!(encode_args.prompt, "--not-a-flag");
+    assert_eq!(encode_args.token_count, 4096);
+
+    let decode_args = DecodeArgs::default();
+    assert_eq!(decode_args.skip_start, encode_args.skip_start);
+    assert_eq!(decode_args.min_p, encode_args.min_p);
+    assert_eq!(decode_args.aux_prompt, DEFAULT_AUX_PROMPT);
+    assert_eq!(decode_args.metric, Metric::Distinguishability);
+}
@@ -0,0 +1,156 @@
+//! The framing of hidden messages.
+//!
+//! Every hidden bitstream starts with a small header describing the payload that follows it:
+//!
+//! | Field   | Size    | Description                                  |
+//! |---------|---------|----------------------------------------------|
+//! | version | 1 byte  | [`FORMAT_VERSION`]                           |
+//! | kind    | 1 byte  | The [`PayloadKind`]                          |
+//! | length  | 4 bytes | The length of the payload in bits            |
+//! | crc     | 4 bytes | The CRC-32 of the header's other fields and  |
+//! |         |         | the payload, padded to bytes                 |
+//!
+//! All integers are little-endian. When a message is encrypted, the header is encrypted along
+//! with the payload, so that the hidden bitstream remains indistinguishable from random data.
+//!
+//! The payload of a [`PayloadKind::Compressed`] message starts with the CRC-32 of the text before
+//! it was compressed. The header's checksum only covers the compressed bits, which robust mode can
+//! recover intact even when the model's predictions differ slightly from the encoder's, so this
+//! tells whether the text was also decompressed correctly.
+
+use anyhow::Result;
+
+use crate::{
+    error::Error,
+    range_coder::{bools_to_bytes, bytes_to_bools},
+};
+
+pub const FORMAT_VERSION: u8 = 1;
+pub const HEADER_BITS: usize = 10 * 8;
+/// The size of the CRC-32 of the text at the start of a [`PayloadKind::Compressed`] payload.
+pub const TEXT_CRC_BITS: usize = 32;
+
+/// How the payload of a hidden message should be interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadKind {
+    /// Bytes that were hidden as-is.
+    Raw = 0,
+    /// Text compressed with the language model.
+    Compressed = 1,
+    /// Bytes compressed with DEFLATE.
+    Deflated = 2,
+}
+
+impl TryFrom<u8> for PayloadKind {
+    type Error = Error;
+
+    fn try_from(kind: u8) -> std::result::Result<Self, Error> {
+        match kind {
+            0 => Ok(PayloadKind::Raw),
+            1 => Ok(PayloadKind::Compressed),
+            2 => Ok(PayloadKind::Deflated),
+            _ => Err(Error::InvalidMessage(format!(
+                "unknown payload kind {kind}"
+            ))),
+        }
+    }
+}
+
+/// A recovered hidden message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    /// A message hidden with [`PayloadKind::Raw`] or [`PayloadKind::Deflated`].
+    Bytes(Vec<u8>),
+    /// A decompressed message hidden with [`PayloadKind::Compressed`].
+    Text(String),
+}
+
+impl Payload {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Payload::Bytes(bytes) => bytes,
+            Payload::Text(text) => text.into_bytes(),
+        }
+    }
+
+    /// Returns the payload as a JSON object, with text as `message` and bytes as an array of
+    /// numbers in `bytes`.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Payload::Bytes(bytes) => serde_json::json!({ "bytes": bytes }),
+            Payload::Text(message) => serde_json::json!({ "message": message }),
+        }
+    }
+}
+
+fn checksum(header: &[u8], payload: &[bool]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(&bools_to_bytes(payload));
+    hasher.finalize()
+}
+
+/// Prefixes `payload` with a header.
+pub fn pack(kind: PayloadKind, payload: &[bool]) -> Vec<bool> {
+    let mut header = vec![FORMAT_VERSION, kind as u8];
+    header.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    header.extend_from_slice(&checksum(&header, payload).to_le_bytes());
+
+    let mut out = bytes_to_bools(&header, None);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Reads the header from the start of `bools`, and returns the payload that it describes. Any
+/// bits after the end of the payload are ignored.
+pub fn unpack(bools: &[bool]) -> Result<(PayloadKind, Vec<bool>)> {
+    if bools.len() < HEADER_BITS {
+        return Err(
+            Error::InvalidMessage("message is too short to contain a header".into()).into(),
+        );
+    }
+    let header = bools_to_bytes(&bools[..HEADER_BITS]);
+
+    if header[0] != FORMAT_VERSION {
+        return Err(Error::InvalidMessage(format!(
+            "unsupported format version {}. The message may be encrypted, or the settings may not \
+             match those used to encode it",
+            header[0]
+        ))
+        .into());
+    }
+    let kind = PayloadKind::try_from(header[1])?;
+    let length = u32::from_le_bytes(header[2..6].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[6..10].try_into().unwrap());
+
+    let Some(payload) = bools.get(HEADER_BITS..HEADER_BITS + length) else {
+        return Err(Error::InvalidMessage("message is truncated".into()).into());
+    };
+    if checksum(&header[..6], payload) != crc {
+        return Err(Error::InvalidMessage("checksum mismatch".into()).into());
+    }
+
+    Ok((kind, payload.to_vec()))
+}
+
+#[test]
+fn test_container() {
+    let payload = bytes_to_bools(b"payload", Some(53));
+
+    let mut packed = pack(PayloadKind::Compressed, &payload);
+    assert_eq!(packed.len(), HEADER_BITS + payload.len());
+    packed.extend([true, false, true]);
+    assert_eq!(
+        unpack(&packed).unwrap(),
+        (PayloadKind::Compressed, payload.clone())
+    );
+
+    for i in [0, 8, 16, 48, HEADER_BITS + 3] {
+        let mut corrupted = packed.clone();
+        corrupted[i] = !corrupted[i];
+        assert!(unpack(&corrupted).is_err());
+    }
+
+    assert!(unpack(&packed[..HEADER_BITS + payload.len() - 1]).is_err());
+    assert!(unpack(&packed[..HEADER_BITS - 1]).is_err());
+}
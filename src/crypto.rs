@@ -0,0 +1,155 @@
+//! Authenticated encryption of hidden messages.
+//!
+//! An encrypted message is laid out as `salt || length || ciphertext || tag`. The key, nonce, and
+//! a mask for the length are all derived from the secret and the random salt, so every part of the
+//! output is indistinguishable from random bytes to anyone without the secret.
+
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use rand::RngCore;
+
+use crate::{
+    error::Error,
+    range_coder::{bools_to_bytes, bytes_to_bools},
+};
+
+const SALT_LEN: usize = 16;
+const LENGTH_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// The number of bytes that encryption adds to a message.
+pub const OVERHEAD: usize = SALT_LEN + LENGTH_LEN + TAG_LEN;
+
+struct DerivedKey {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; NONCE_LEN],
+    length_mask: [u8; LENGTH_LEN],
+}
+
+fn derive_key(secret: &[u8], salt: &[u8]) -> Result<DerivedKey> {
+    let mut out = [0; KEY_LEN + NONCE_LEN + LENGTH_LEN];
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut out)
+        .map_err(|e| anyhow!("Could not derive key: {e}"))?;
+
+    let (key, rest) = out.split_at(KEY_LEN);
+    let (nonce, length_mask) = rest.split_at(NONCE_LEN);
+
+    Ok(DerivedKey {
+        cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        nonce: nonce.try_into().unwrap(),
+        length_mask: length_mask.try_into().unwrap(),
+    })
+}
+
+fn mask_length(length: [u8; LENGTH_LEN], mask: &[u8; LENGTH_LEN]) -> [u8; LENGTH_LEN] {
+    std::array::from_fn(|i| length[i] ^ mask[i])
+}
+
+/// Encrypts and authenticates `message` with a key derived from `secret`.
+pub fn encrypt(secret: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let mut salt = [0; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+
+    let key = derive_key(secret, &salt)?;
+    let length = mask_length((message.len() as u32).to_le_bytes(), &key.length_mask);
+
+    let mut ciphertext = message.to_vec();
+    let tag = key
+        .cipher
+        .encrypt_in_place_detached(Nonce::from_slice(&key.nonce), &length, &mut ciphertext)
+        .map_err(|e| anyhow!("Could not encrypt message: {e}"))?;
+
+    let mut out = Vec::with_capacity(message.len() + OVERHEAD);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&length);
+    out.extend_from_slice(&ciphertext);
+    out.extend_from_slice(&tag);
+
+    Ok(out)
+}
+
+/// Decrypts a message encrypted with [`encrypt`], verifying that it has not been modified. Any
+/// bytes following the encrypted message are ignored.
+pub fn decrypt(secret: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let (Some(salt), Some(length)) = (
+        data.get(..SALT_LEN),
+        data.get(SALT_LEN..SALT_LEN + LENGTH_LEN),
+    ) else {
+        return Err(Error::DecryptionFailed.into());
+    };
+    let key = derive_key(secret, salt)?;
+    let message_len =
+        u32::from_le_bytes(mask_length(length.try_into().unwrap(), &key.length_mask)) as usize;
+
+    let start = SALT_LEN + LENGTH_LEN;
+    let (Some(ciphertext), Some(tag)) = (
+        data.get(start..start + message_len),
+        data.get(start + message_len..start + message_len + TAG_LEN),
+    ) else {
+        return Err(Error::DecryptionFailed.into());
+    };
+
+    let mut message = ciphertext.to_vec();
+    key.cipher
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&key.nonce),
+            length,
+            &mut message,
+            Tag::from_slice(tag),
+        )
+        .map_err(|_| Error::DecryptionFailed)?;
+
+    Ok(message)
+}
+
+/// Encrypts a bitstream with [`encrypt`]. The bitstream is padded with zeros to a whole number of
+/// bytes.
+pub fn encrypt_bools(secret: &[u8], bools: &[bool]) -> Result<Vec<bool>> {
+    Ok(bytes_to_bools(
+        &encrypt(secret, &bools_to_bytes(bools))?,
+        None,
+    ))
+}
+
+/// Decrypts a bitstream encrypted with [`encrypt_bools`].
+pub fn decrypt_bools(secret: &[u8], bools: &[bool]) -> Result<Vec<bool>> {
+    Ok(bytes_to_bools(
+        &decrypt(secret, &bools_to_bytes(bools))?,
+        None,
+    ))
+}
+
+#[test]
+fn test_encryption() {
+    let message = b"This is a secret.";
+
+    let mut encrypted = encrypt(b"passphrase", message).unwrap();
+    assert_eq!(encrypted.len(), message.len() + OVERHEAD);
+    assert_ne!(encrypt(b"passphrase", message).unwrap(), encrypted);
+
+    encrypted.extend_from_slice(b"trailing data");
+    assert_eq!(decrypt(b"passphrase", &encrypted).unwrap(), message);
+
+    let error = decrypt(b"wrong passphrase", &encrypted).unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::DecryptionFailed)));
+
+    for i in [
+        0,
+        SALT_LEN,
+        SALT_LEN + LENGTH_LEN,
+        message.len() + OVERHEAD - 1,
+    ] {
+        let mut tampered = encrypted.clone();
+        tampered[i] ^= 1;
+        assert!(decrypt(b"passphrase", &tampered).is_err());
+    }
+
+    assert!(decrypt(b"passphrase", &encrypted[..OVERHEAD]).is_err());
+}
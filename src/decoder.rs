@@ -6,6 +6,7 @@ const REPLACEMENT_CHARACTER_BYTES: &[u8] = REPLACEMENT_CHARACTER_STR.as_bytes();
 /// A struct used to decode [`Vec<u8>`] tokens into [`String`] tokens.
 ///
 /// This struct can handle merging split UTF-8 codepoints.
+#[derive(Default)]
 pub struct TokenDecoder {
     buf: Vec<u8>,
     safe_len: usize,
@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// Errors returned by the [`Stegano`](crate::Stegano) API.
+#[derive(Debug)]
+pub enum Error {
+    /// The generation ended before the entire message could be hidden in it. Allowing more
+    /// tokens or using a prompt that produces longer text may help.
+    MessageTooLong,
+    /// The text contains a token that could not have been produced by the encoder under the given
+    /// settings. This usually means that the text, model, or settings differ from those used to
+    /// encode it.
+    TokenFilteredOut,
+    /// The hidden message could not be decrypted, either because the key is wrong, the message was
+    /// not encrypted, or the text has been modified.
+    DecryptionFailed,
+    /// The hidden message was recovered, but it is not valid for the requested format.
+    InvalidMessage(String),
+    /// The generated text does not tokenize into the tokens that it was generated as, starting at
+    /// the token with this index, so it could not be decoded. A different prompt or seed may help.
+    RetokenizationMismatch(usize),
+    /// In robust mode, the text could not be generated without a token at this index whose
+    /// decoding could change with tiny differences in the model's predictions, so it may not be
+    /// decoded on other machines. A smaller robust margin or a different prompt may help.
+    FragileToken(usize),
+    /// An error from the model backend or another unexpected source.
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MessageTooLong => write!(f, "Could not encode entire message!"),
+            Error::TokenFilteredOut => write!(f, "Token was filtered out"),
+            Error::DecryptionFailed => write!(f, "Could not decrypt message"),
+            Error::InvalidMessage(reason) => write!(f, "Invalid message: {reason}"),
+            Error::RetokenizationMismatch(position) => write!(
+                f,
+                "The generated text tokenizes differently from token {position} onwards, so it \
+                 could not be decoded"
+            ),
+            Error::FragileToken(position) => write!(
+                f,
+                "Token {position} could be decoded differently on other machines, so robust mode \
+                 could not be satisfied"
+            ),
+            Error::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// Recovers an [`Error`] that was raised inside of an [`anyhow::Error`], or wraps the error as
+    /// [`Error::Other`].
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast().unwrap_or_else(Error::Other)
+    }
+}
@@ -0,0 +1,124 @@
+//! Estimating how much generated text is needed to hide a message.
+
+use std::fmt;
+
+use serde::Serialize;
+
+/// The z-score bounding the central 95% of a normal distribution.
+const Z_95: f64 = 1.96;
+
+/// An estimate of the number of tokens needed to hide a message, based on the number of bits
+/// hidden in each of a sample of generated tokens.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Estimate {
+    /// The number of bits to hide, including the header and any encryption and error correction
+    /// overhead.
+    pub message_bits: usize,
+    /// The number of tokens at the start of the text that do not hide any bits.
+    pub skip_start: usize,
+    /// The number of tokens that the rate was measured over.
+    pub sampled_tokens: usize,
+    /// The mean number of bits hidden per token.
+    pub bits_per_token: f64,
+    /// The standard deviation of the number of bits hidden per token.
+    pub std_dev: f64,
+}
+
+impl Estimate {
+    /// Creates an estimate from the number of bits hidden by each sampled token.
+    pub fn new(message_bits: usize, skip_start: usize, samples: &[f64]) -> Self {
+        let n = samples.len() as f64;
+        let bits_per_token = samples.iter().sum::<f64>() / n.max(1.);
+        let variance = samples
+            .iter()
+            .map(|x| (x - bits_per_token).powi(2))
+            .sum::<f64>()
+            / (n - 1.).max(1.);
+
+        Self {
+            message_bits,
+            skip_start,
+            sampled_tokens: samples.len(),
+            bits_per_token,
+            std_dev: variance.sqrt(),
+        }
+    }
+
+    /// Returns the number of tokens after which the total number of bits hidden is expected to
+    /// reach the message length, when it is `z` standard deviations above its mean.
+    fn tokens_at(&self, z: f64) -> f64 {
+        if self.bits_per_token <= 0. {
+            return f64::INFINITY;
+        }
+
+        // Over n tokens, the mean is `bits_per_token * n` and the standard deviation is
+        // `std_dev * sqrt(n)`, so this solves a quadratic in sqrt(n).
+        let a = self.bits_per_token;
+        let b = z * self.std_dev;
+        let c = self.message_bits as f64;
+        let sqrt_n = (-b + (b * b + 4. * a * c).sqrt()) / (2. * a);
+
+        self.skip_start as f64 + sqrt_n * sqrt_n
+    }
+
+    /// The expected number of tokens needed to hide the message.
+    pub fn expected_tokens(&self) -> f64 {
+        self.tokens_at(0.)
+    }
+
+    /// A range that the number of tokens needed falls within 95% of the time, assuming that the
+    /// number of bits hidden by each token is independent.
+    pub fn token_bounds(&self) -> (f64, f64) {
+        (self.tokens_at(Z_95), self.tokens_at(-Z_95))
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (low, high) = self.token_bounds();
+
+        writeln!(f, "Message: {} bits", self.message_bits)?;
+        writeln!(
+            f,
+            "Rate: {:.3} bits per token (standard deviation {:.3}, {} tokens sampled)",
+            self.bits_per_token, self.std_dev, self.sampled_tokens
+        )?;
+        write!(
+            f,
+            "Expected length: {:.0} tokens (95% range: {:.0} to {:.0} tokens)",
+            self.expected_tokens(),
+            low,
+            high
+        )
+    }
+}
+
+impl Estimate {
+    /// Returns the estimate as a JSON object, including the expected number of tokens and its
+    /// 95% range.
+    pub fn to_json(&self) -> serde_json::Value {
+        let (low, high) = self.token_bounds();
+
+        let mut json = serde_json::to_value(self).expect("Estimates should always serialize");
+        json["expected_tokens"] = self.expected_tokens().into();
+        json["low_tokens"] = low.into();
+        json["high_tokens"] = high.into();
+        json
+    }
+}
+
+#[test]
+fn test_estimate() {
+    let estimate = Estimate::new(200, 8, &[1., 3., 2., 2.]);
+    assert_eq!(estimate.bits_per_token, 2.);
+    assert_eq!(estimate.expected_tokens(), 108.);
+
+    let (low, high) = estimate.token_bounds();
+    assert!(8. < low && low < 108. && 108. < high);
+
+    let estimate = Estimate::new(200, 8, &[2., 2., 2., 2.]);
+    assert_eq!(estimate.token_bounds(), (108., 108.));
+
+    let estimate = Estimate::new(200, 8, &[0., 0.]);
+    assert_eq!(estimate.expected_tokens(), f64::INFINITY);
+}
@@ -0,0 +1,334 @@
+//! Forward error correction for hidden bitstreams.
+//!
+//! With FEC enabled, the hidden bits are split into shards, and Reed-Solomon parity shards are
+//! added so that the message can be rebuilt when some shards are lost to edits of the cover text.
+//! Each shard is hidden as a separate segment of the range coder (see
+//! [`RangeDecoder::segmented`](crate::range_coder::RangeDecoder::segmented)), so an edit only
+//! corrupts the shards hidden in the tokens whose predictions it changes. Language models look at
+//! the whole text, so in practice these are often all of the later tokens, unless the changes are
+//! small enough for robust mode to absorb. Every shard has the following layout:
+//!
+//! | Field  | Size     | Description                                       |
+//! |--------|----------|---------------------------------------------------|
+//! | index  | 2 bytes  | The index of the shard                            |
+//! | data   | 2 bytes  | The number of data shards in the message          |
+//! | parity | 1 byte   | The number of parity shards in each group         |
+//! | body   | 32 bytes | Part of the message, or parity                    |
+//! | crc    | 4 bytes  | The CRC-32 of the shard's other fields            |
+//!
+//! All integers are little-endian. Data shards are split into groups of [`GROUP_DATA_SHARDS`],
+//! each with its own parity shards, and the shards of different groups are interleaved so that a
+//! long edit is spread across groups. Data shards are numbered first, followed by the parity
+//! shards of each group in turn.
+//!
+//! When decoding, the decoder does not know where a corrupted shard ended, so after failing to
+//! read a shard it tries every later token as the start of the next one. The CRC makes it
+//! unlikely that a shard is read from the wrong position.
+
+use std::{collections::HashMap, ops::Range};
+
+use anyhow::Result;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::{
+    error::Error,
+    range_coder::{bools_to_bytes, bytes_to_bools, RangeEncoder},
+    steganography::Interval,
+};
+
+/// The number of data shards that share parity shards.
+pub const GROUP_DATA_SHARDS: usize = 16;
+/// The number of bytes of the message in each shard.
+const BODY_BYTES: usize = 32;
+const HEADER_BYTES: usize = 5;
+const SHARD_BYTES: usize = HEADER_BYTES + BODY_BYTES + 4;
+/// The number of bits hidden in each segment of the range coder.
+pub const SEGMENT_BITS: usize = SHARD_BYTES * 8;
+
+/// The most parity shards that each group can have.
+pub const MAX_PARITY: u8 = (256 - GROUP_DATA_SHARDS) as u8;
+
+/// The numbers of shards in a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Layout {
+    n_data: usize,
+    parity: usize,
+}
+
+impl Layout {
+    fn n_groups(&self) -> usize {
+        self.n_data.div_ceil(GROUP_DATA_SHARDS)
+    }
+
+    fn n_shards(&self) -> usize {
+        self.n_data + self.n_groups() * self.parity
+    }
+
+    /// The indices of the data shards of group `g`.
+    fn data(&self, g: usize) -> Range<usize> {
+        g * GROUP_DATA_SHARDS..((g + 1) * GROUP_DATA_SHARDS).min(self.n_data)
+    }
+
+    /// The indices of the parity shards of group `g`.
+    fn parity(&self, g: usize) -> Range<usize> {
+        let start = self.n_data + g * self.parity;
+        start..start + self.parity
+    }
+
+    /// The indices of all shards in the order that they are hidden, taking one shard from each
+    /// group in turn.
+    fn order(&self) -> Vec<usize> {
+        let groups = (0..self.n_groups())
+            .map(|g| self.data(g).chain(self.parity(g)).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        (0..GROUP_DATA_SHARDS + self.parity)
+            .flat_map(|row| {
+                groups
+                    .iter()
+                    .filter_map(move |group| group.get(row).copied())
+            })
+            .collect()
+    }
+
+    fn codec(&self, g: usize) -> Result<ReedSolomon> {
+        Ok(ReedSolomon::new(self.data(g).len(), self.parity)?)
+    }
+}
+
+/// A shard read from the cover text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Shard {
+    index: usize,
+    layout: Layout,
+    body: Vec<u8>,
+}
+
+impl Shard {
+    fn to_bools(&self) -> Vec<bool> {
+        let mut bytes = Vec::with_capacity(SHARD_BYTES);
+        bytes.extend_from_slice(&(self.index as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.layout.n_data as u16).to_le_bytes());
+        bytes.push(self.layout.parity as u8);
+        bytes.extend_from_slice(&self.body);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_le_bytes());
+
+        bytes_to_bools(&bytes, None)
+    }
+
+    fn from_bools(bools: &[bool]) -> Option<Self> {
+        let bytes = bools_to_bytes(bools);
+        let (fields, crc) = bytes.split_at(SHARD_BYTES - 4);
+        if crc32fast::hash(fields).to_le_bytes() != crc {
+            return None;
+        }
+
+        let shard = Self {
+            index: u16::from_le_bytes([fields[0], fields[1]]) as usize,
+            layout: Layout {
+                n_data: u16::from_le_bytes([fields[2], fields[3]]) as usize,
+                parity: fields[4] as usize,
+            },
+            body: fields[HEADER_BYTES..].to_vec(),
+        };
+
+        (shard.layout.n_data > 0 && shard.index < shard.layout.n_shards()).then_some(shard)
+    }
+}
+
+/// The number of bits hidden for a message of `n_bits` bits with `parity` parity shards per group.
+pub fn encoded_bits(n_bits: usize, parity: u8) -> usize {
+    let layout = Layout {
+        n_data: n_bits.div_ceil(BODY_BYTES * 8).max(1),
+        parity: parity as usize,
+    };
+    layout.n_shards() * SEGMENT_BITS
+}
+
+/// Splits `bools` into shards and adds `parity` parity shards to each group of them. Returns the
+/// shards in the order that they should be hidden.
+pub fn encode(bools: &[bool], parity: u8) -> Result<Vec<Vec<bool>>> {
+    let mut bytes = bools_to_bytes(bools);
+    let layout = Layout {
+        n_data: bytes.len().div_ceil(BODY_BYTES).max(1),
+        parity: parity as usize,
+    };
+    if layout.n_shards() > u16::MAX as usize {
+        return Err(Error::MessageTooLong.into());
+    }
+    bytes.resize(layout.n_data * BODY_BYTES, 0);
+
+    let mut bodies = bytes
+        .chunks(BODY_BYTES)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    bodies.resize(layout.n_shards(), vec![0; BODY_BYTES]);
+
+    for g in (0..layout.n_groups()).filter(|_| layout.parity > 0) {
+        let mut shards = layout
+            .data(g)
+            .chain(layout.parity(g))
+            .map(|i| std::mem::take(&mut bodies[i]))
+            .collect::<Vec<_>>();
+        layout.codec(g)?.encode(&mut shards)?;
+
+        for (i, shard) in layout.data(g).chain(layout.parity(g)).zip(shards) {
+            bodies[i] = shard;
+        }
+    }
+
+    Ok(layout
+        .order()
+        .into_iter()
+        .map(|index| {
+            Shard {
+                index,
+                layout,
+                body: std::mem::take(&mut bodies[index]),
+            }
+            .to_bools()
+        })
+        .collect())
+}
+
+/// Reads a shard from the intervals of the tokens starting at the start of its segment. Returns
+/// the shard and the number of tokens it was hidden in, or `None` if it was corrupted.
+fn read_shard(symbols: &[Option<Interval>]) -> Option<(Shard, usize)> {
+    let mut encoder = RangeEncoder::new();
+
+    for (i, symbol) in symbols.iter().enumerate() {
+        let interval = (*symbol)?;
+        encoder.encode_range(interval.low, interval.high, interval.denominator);
+
+        if encoder.bits_written() > SEGMENT_BITS + 1 {
+            let bools = encoder.flush();
+            return Shard::from_bools(&bools[..SEGMENT_BITS]).map(|shard| (shard, i + 1));
+        }
+    }
+
+    None
+}
+
+/// Recovers the bits hidden by [`encode`], given the interval of each token that hid part of them,
+/// or `None` for tokens that could not have hidden part of them.
+pub fn decode(symbols: &[Option<Interval>]) -> Result<Vec<bool>> {
+    let mut shards = Vec::new();
+    let mut start = 0;
+
+    while start < symbols.len() {
+        match read_shard(&symbols[start..]) {
+            Some((shard, len)) => {
+                shards.push(shard);
+                start += len;
+            }
+            None => start += 1,
+        }
+    }
+
+    // A shard read from the wrong position could pass its CRC by chance, so the layout is taken
+    // from the majority of shards.
+    let mut votes = HashMap::<Layout, usize>::new();
+    for shard in &shards {
+        *votes.entry(shard.layout).or_default() += 1;
+    }
+    let Some((layout, _)) = votes.into_iter().max_by_key(|&(_, votes)| votes) else {
+        return Err(Error::InvalidMessage("no intact shards were found".into()).into());
+    };
+
+    let mut bodies = vec![None; layout.n_shards()];
+    for shard in shards.into_iter().filter(|shard| shard.layout == layout) {
+        bodies[shard.index].get_or_insert(shard.body);
+    }
+
+    let mut bytes = Vec::with_capacity(layout.n_data * BODY_BYTES);
+    for g in 0..layout.n_groups() {
+        let mut group = layout
+            .data(g)
+            .chain(layout.parity(g))
+            .map(|i| bodies[i].take())
+            .collect::<Vec<_>>();
+        let lost = group.iter().filter(|shard| shard.is_none()).count();
+
+        if lost > layout.parity {
+            return Err(Error::InvalidMessage(format!(
+                "{lost} of the {} shards in group {g} were lost, but only {} can be recovered",
+                group.len(),
+                layout.parity
+            ))
+            .into());
+        }
+        if lost > 0 {
+            layout.codec(g)?.reconstruct_data(&mut group)?;
+        }
+
+        for shard in group.into_iter().take(layout.data(g).len()) {
+            bytes.extend(shard.expect("Data shards should have been reconstructed"));
+        }
+    }
+
+    Ok(bytes_to_bools(&bytes, None))
+}
+
+/// Hides `segments` with a range decoder using a fixed distribution, and returns the interval of
+/// each symbol.
+#[cfg(test)]
+fn test_symbols(segments: Vec<Vec<bool>>) -> Vec<Option<Interval>> {
+    use crate::range_coder::RangeDecoder;
+
+    let table = [0, 3, 5, 10, 15];
+    let mut decoder = RangeDecoder::segmented(segments);
+    let mut symbols = Vec::new();
+
+    while !decoder.is_done() {
+        let i = decoder.decode(&table, 16);
+        symbols.push(Some(Interval {
+            low: table[i],
+            high: table.get(i + 1).copied().unwrap_or(16),
+            denominator: 16,
+        }));
+    }
+
+    symbols
+}
+
+#[cfg(test)]
+fn changed(symbol: Option<Interval>) -> Option<Interval> {
+    let interval = symbol.unwrap();
+    let (low, high) = if interval.low == 0 { (10, 15) } else { (0, 3) };
+    Some(Interval {
+        low,
+        high,
+        ..interval
+    })
+}
+
+#[test]
+fn test_fec() {
+    let message = (0..2000).map(|i| i % 3 == 0).collect::<Vec<_>>();
+
+    let segments = encode(&message, 3).unwrap();
+    assert_eq!(
+        segments.len() * SEGMENT_BITS,
+        encoded_bits(message.len(), 3)
+    );
+    let symbols = test_symbols(segments);
+
+    let decoded = decode(&symbols).unwrap();
+    assert_eq!(&decoded[..message.len()], message.as_slice());
+    assert!(decoded[message.len()..].iter().all(|&b| !b));
+
+    // A changed symbol, a deleted symbol, and a filtered-out symbol each lose one shard.
+    let mut edited = symbols.clone();
+    edited[10] = changed(edited[10]);
+    edited.remove(symbols.len() / 2);
+    edited[symbols.len() - 50] = None;
+    let decoded = decode(&edited).unwrap();
+    assert_eq!(&decoded[..message.len()], message.as_slice());
+
+    // Without parity, the same edits cannot be recovered from.
+    let symbols = test_symbols(encode(&message, 0).unwrap());
+    let mut edited = symbols.clone();
+    edited[10] = changed(edited[10]);
+    let error = decode(&edited).unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::InvalidMessage(_))));
+}
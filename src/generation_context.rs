@@ -1,23 +1,50 @@
-use std::{borrow::Cow, sync::atomic::AtomicBool};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufReader, Read, Write},
+    num::NonZeroU32,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, OnceLock},
+    time::UNIX_EPOCH,
+};
 
 use crate::decoder::TokenDecoder;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use blake2::{digest::consts::U32, Blake2b, Digest};
 use llama_cpp_2::{
     context::{params::LlamaContextParams, LlamaContext},
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
-    model::{AddBos, LlamaModel, Special},
+    model::{params::LlamaModelParams, AddBos, LlamaChatMessage, LlamaModel, Special},
     sampling::LlamaSampler,
-    token::{data_array::LlamaTokenDataArray, LlamaToken},
+    token::{data::LlamaTokenData, data_array::LlamaTokenDataArray, LlamaToken},
 };
 
 pub struct GenerationContext<'a> {
+    model: &'a Model,
     context: LlamaContext<'a>,
     tokens: Vec<LlamaToken>,
+    /// The logits after the last token, copied out of the context so that they survive restoring
+    /// a snapshot.
+    logits: Vec<f32>,
     batch: LlamaBatch,
     params: LlamaContextParams,
 }
 
+/// A saved state of a [`GenerationContext`], created by [`LanguageModel::snapshot`].
+pub struct ContextSnapshot {
+    tokens: Vec<LlamaToken>,
+    logits: Vec<f32>,
+    state: Vec<u8>,
+}
+
+/// A saved state of a [`MultiGenerationContext`], created by [`Sequences::snapshot`].
+pub struct SequencesSnapshot {
+    tokens: Vec<Vec<LlamaToken>>,
+    logits: Vec<Vec<f32>>,
+}
+
 static BACKEND_SET: AtomicBool = AtomicBool::new(false);
 static BACKEND: LlamaBackend = LlamaBackend {};
 
@@ -28,26 +55,327 @@ pub fn get_backend() -> Result<&'static LlamaBackend> {
     Ok(&BACKEND)
 }
 
+/// BLAKE2b with a 256-bit output.
+pub type Blake2b256 = Blake2b<U32>;
+
+/// A model loaded by [`load_model`], along with the file that it was loaded from.
+pub struct Model {
+    model: LlamaModel,
+    path: PathBuf,
+    fingerprint: OnceLock<[u8; 32]>,
+}
+
+impl Deref for Model {
+    type Target = LlamaModel;
+
+    fn deref(&self) -> &LlamaModel {
+        &self.model
+    }
+}
+
+/// Loads a GGUF model file, optionally offloading all of its layers to the GPU.
+pub fn load_model(path: impl AsRef<Path>, gpu: bool) -> Result<Model> {
+    let model = LlamaModel::load_from_file(
+        get_backend()?,
+        &path,
+        &LlamaModelParams::default().with_n_gpu_layers(gpu as u32 * 1000),
+    )?;
+
+    Ok(Model {
+        model,
+        path: path.as_ref().to_path_buf(),
+        fingerprint: OnceLock::new(),
+    })
+}
+
+/// A reader that hashes everything that is read through it.
+struct HashingReader<R> {
+    reader: R,
+    hasher: Blake2b256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn skip_bytes(reader: &mut impl Read, n: u64) -> Result<()> {
+    let skipped = std::io::copy(&mut reader.take(n), &mut std::io::sink())?;
+    if skipped != n {
+        bail!("unexpected end of file");
+    }
+    Ok(())
+}
+
+/// The GGUF metadata value type of strings, which are also used for keys and tensor names.
+const GGUF_STRING: u32 = 8;
+/// The GGUF metadata value type of arrays.
+const GGUF_ARRAY: u32 = 9;
+
+/// Returns the size of a GGUF metadata value of type `value_type`, if all of them have the same
+/// size.
+fn gguf_value_size(value_type: u32) -> Result<Option<u64>> {
+    Ok(match value_type {
+        // Integers, floats and booleans.
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        GGUF_STRING | GGUF_ARRAY => None,
+        _ => bail!("unknown GGUF value type {value_type}"),
+    })
+}
+
+/// Reads past a GGUF metadata value of type `value_type`.
+fn skip_gguf_value(reader: &mut impl Read, value_type: u32) -> Result<()> {
+    if let Some(size) = gguf_value_size(value_type)? {
+        return skip_bytes(reader, size);
+    }
+    if value_type == GGUF_STRING {
+        let len = read_u64(reader)?;
+        return skip_bytes(reader, len);
+    }
+
+    let element_type = read_u32(reader)?;
+    let len = read_u64(reader)?;
+    if let Some(size) = gguf_value_size(element_type)? {
+        return skip_bytes(reader, len.saturating_mul(size));
+    }
+    for _ in 0..len {
+        skip_gguf_value(reader, element_type)?;
+    }
+    Ok(())
+}
+
+/// Hashes the size and modification time of the GGUF file at `path`, and everything in it before
+/// the tensor data: its header, metadata, and the names, shapes, types and offsets of its tensors.
+fn gguf_fingerprint(path: &Path) -> Result<[u8; 32]> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+
+    let mut hasher = Blake2b256::new();
+    hasher.update(metadata.len().to_le_bytes());
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    hasher.update(modified.as_nanos().to_le_bytes());
+
+    let mut reader = HashingReader {
+        reader: BufReader::new(file),
+        hasher,
+    };
+    if read_u32(&mut reader)? != u32::from_le_bytes(*b"GGUF") {
+        bail!("not a GGUF file");
+    }
+    let version = read_u32(&mut reader)?;
+    if version < 2 {
+        bail!("unsupported GGUF version {version}");
+    }
+    let n_tensors = read_u64(&mut reader)?;
+    let n_values = read_u64(&mut reader)?;
+
+    for _ in 0..n_values {
+        skip_gguf_value(&mut reader, GGUF_STRING)?;
+        let value_type = read_u32(&mut reader)?;
+        skip_gguf_value(&mut reader, value_type)?;
+    }
+    for _ in 0..n_tensors {
+        skip_gguf_value(&mut reader, GGUF_STRING)?;
+        let n_dims = read_u32(&mut reader)?;
+        // The dimensions, type and offset.
+        skip_bytes(&mut reader, n_dims as u64 * 8 + 4 + 8)?;
+    }
+
+    Ok(reader.hasher.finalize().into())
+}
+
+/// The context parameters used by the command-line application.
+pub fn default_context_params() -> LlamaContextParams {
+    LlamaContextParams::default()
+        .with_n_ctx(std::num::NonZeroU32::new(8192))
+        .with_n_threads(4)
+        .with_n_threads_batch(8)
+        .with_n_batch(2048)
+}
+
+/// The tokenizer and special tokens of a language model.
+pub trait Vocabulary {
+    fn token_bos(&self) -> LlamaToken;
+    fn token_eos(&self) -> LlamaToken;
+    fn is_eog_token(&self, token: LlamaToken) -> bool;
+
+    fn token_to_bytes(&self, token: LlamaToken) -> Result<Vec<u8>>;
+    /// Tokenizes `text` without adding a BOS token.
+    fn str_to_token(&self, text: &str) -> Result<Vec<LlamaToken>>;
+    /// Formats `prompt` as a user message using the model's chat template, leaving the assistant's
+    /// response open.
+    fn apply_chat_template(&self, prompt: &str) -> Result<String>;
+    /// A hash identifying the model, used to tell whether saved state belongs to it.
+    fn fingerprint(&self) -> Result<[u8; 32]>;
+}
+
+impl Vocabulary for Model {
+    fn token_bos(&self) -> LlamaToken {
+        self.model.token_bos()
+    }
+
+    fn token_eos(&self) -> LlamaToken {
+        self.model.token_eos()
+    }
+
+    fn is_eog_token(&self, token: LlamaToken) -> bool {
+        self.model.is_eog_token(token)
+    }
+
+    fn token_to_bytes(&self, token: LlamaToken) -> Result<Vec<u8>> {
+        Ok(self.model.token_to_bytes(token, Special::Tokenize)?)
+    }
+
+    fn str_to_token(&self, text: &str) -> Result<Vec<LlamaToken>> {
+        Ok(self.model.str_to_token(text, AddBos::Never)?)
+    }
+
+    fn apply_chat_template(&self, prompt: &str) -> Result<String> {
+        Ok(self.model.apply_chat_template(
+            &self.model.chat_template(None)?,
+            &[LlamaChatMessage::new(
+                "user".to_string(),
+                prompt.to_string(),
+            )?],
+            true,
+        )?)
+    }
+
+    /// Hashes the model file's size and modification time, and its GGUF metadata and tensor
+    /// layout, which only takes reading the start of the file rather than all of its weights. A
+    /// model whose weights were changed in place, without changing its size, modification time, or
+    /// metadata, is not told apart. The hash is computed the first time it is needed.
+    fn fingerprint(&self) -> Result<[u8; 32]> {
+        if let Some(fingerprint) = self.fingerprint.get() {
+            return Ok(*fingerprint);
+        }
+
+        let fingerprint = gguf_fingerprint(&self.path)
+            .with_context(|| format!("Could not read {}", self.path.display()))?;
+        Ok(*self.fingerprint.get_or_init(|| fingerprint))
+    }
+}
+
+impl<V: Vocabulary + ?Sized> Vocabulary for &V {
+    fn token_bos(&self) -> LlamaToken {
+        (**self).token_bos()
+    }
+
+    fn token_eos(&self) -> LlamaToken {
+        (**self).token_eos()
+    }
+
+    fn is_eog_token(&self, token: LlamaToken) -> bool {
+        (**self).is_eog_token(token)
+    }
+
+    fn token_to_bytes(&self, token: LlamaToken) -> Result<Vec<u8>> {
+        (**self).token_to_bytes(token)
+    }
+
+    fn str_to_token(&self, text: &str) -> Result<Vec<LlamaToken>> {
+        (**self).str_to_token(text)
+    }
+
+    fn apply_chat_template(&self, prompt: &str) -> Result<String> {
+        (**self).apply_chat_template(prompt)
+    }
+
+    fn fingerprint(&self) -> Result<[u8; 32]> {
+        (**self).fingerprint()
+    }
+}
+
+/// Stores `key` as tokens, to label a llama.cpp session file.
+fn key_tokens(key: &[u8; 32]) -> Vec<LlamaToken> {
+    key.chunks_exact(4)
+        .map(|bytes| LlamaToken(i32::from_le_bytes(bytes.try_into().unwrap())))
+        .collect()
+}
+
+fn token_data_from_logits(logits: &[f32]) -> LlamaTokenDataArray {
+    LlamaTokenDataArray::from_iter(
+        logits
+            .iter()
+            .enumerate()
+            .map(|(i, logit)| LlamaTokenData::new(LlamaToken(i as i32), *logit, 0.)),
+        false,
+    )
+}
+
+/// Returns the number of tokens at the start of `old_tokens` that can be kept when changing a
+/// context's tokens to `new_tokens`. The last new token is always re-evaluated, so that its
+/// predictions are available.
+fn reusable_tokens(old_tokens: &[LlamaToken], new_tokens: &[LlamaToken]) -> usize {
+    let i = old_tokens
+        .iter()
+        .zip(new_tokens)
+        .position(|(t1, t2)| t1.0 != t2.0)
+        .unwrap_or(old_tokens.len().min(new_tokens.len()));
+
+    if i == new_tokens.len() && i < old_tokens.len() {
+        new_tokens.len().saturating_sub(1)
+    } else {
+        i
+    }
+}
+
 pub trait LanguageModel {
+    /// A cheaply-copyable handle to the model's vocabulary, which does not borrow the context.
+    type Model: Vocabulary + Copy;
+    /// Several sequences evaluated by the same model, as created by
+    /// [`LanguageModel::sequences`].
+    type Sequences: Sequences<Model = Self::Model>;
+    /// A saved state of the context, which it can be returned to with [`LanguageModel::restore`].
+    type Snapshot;
+
     fn partial_clone(&self) -> Result<Self>
     where
         Self: Sized;
+    /// Creates `n` new sequences that use the same model as this context, each containing only a
+    /// BOS token and holding up to `n_ctx` tokens.
+    fn sequences(&self, n: usize, n_ctx: usize) -> Result<Self::Sequences>;
     fn add_tokens(&mut self, tokens: &[LlamaToken]) -> Result<()>;
     fn truncate_tokens(&mut self, length: usize) -> Result<()>;
+    /// Saves the context's tokens along with their evaluated state.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Returns the context to the state saved in `snapshot` without evaluating any tokens, so that
+    /// several continuations can be tried from the same point.
+    fn restore(&mut self, snapshot: &Self::Snapshot) -> Result<()>;
 
     fn tokens(&self) -> &[LlamaToken];
     fn get_token_data(&self) -> LlamaTokenDataArray;
-    fn model(&self) -> &LlamaModel;
+    fn model(&self) -> Self::Model;
+    /// The maximum number of tokens, including the BOS token, that the context can hold.
+    fn context_size(&self) -> usize;
 
     fn tokenize(&self, text: &str) -> Result<Vec<LlamaToken>> {
-        let mut out = self.model().str_to_token(text, AddBos::Never)?;
+        let mut out = self.model().str_to_token(text)?;
         out.insert(0, self.model().token_bos());
         Ok(out)
     }
     fn detokenize(&self, tokens: &[LlamaToken]) -> Result<String> {
         let bytes = tokens
             .iter()
-            .flat_map(|token| self.model().token_to_bytes(*token, Special::Tokenize))
+            .flat_map(|token| self.model().token_to_bytes(*token))
             .flatten()
             .collect::<Vec<u8>>();
 
@@ -67,23 +395,13 @@ pub trait LanguageModel {
         self.detokenize(self.tokens())
     }
     fn set_tokens(&mut self, new_tokens: &[LlamaToken]) -> Result<()> {
-        let n_tokens = {
-            let i = self
-                .tokens()
-                .iter()
-                .zip(new_tokens)
-                .position(|(t1, t2)| t1.0 != t2.0)
-                .unwrap_or(self.tokens().len().min(new_tokens.len()));
-
-            if i == new_tokens.len() && i < self.tokens().len() {
-                new_tokens.len().saturating_sub(1)
-            } else {
-                i
-            }
-        };
+        let n_tokens = reusable_tokens(self.tokens(), new_tokens);
         self.truncate_tokens(n_tokens)?;
         self.add_tokens(&new_tokens[n_tokens..])
     }
+    /// Adds `tokens` one at a time, returning the predictions before each of them and after the
+    /// last. llama.cpp does not guarantee the same logits when tokens are evaluated in batches of
+    /// different sizes, so tokens are evaluated the same way as when they are generated.
     fn add_tokens_get_token_data(
         &mut self,
         tokens: &[LlamaToken],
@@ -130,9 +448,136 @@ pub trait LanguageModel {
     }
 }
 
-impl LanguageModel for GenerationContext<'_> {
+/// Several token sequences that are evaluated by the same model, and which are always extended
+/// with the same tokens.
+pub trait Sequences {
+    type Model: Vocabulary + Copy;
+    /// A saved state of the sequences, which they can be returned to with [`Sequences::restore`].
+    type Snapshot;
+
+    fn n_sequences(&self) -> usize;
+    fn set_tokens(&mut self, seq: usize, tokens: &[LlamaToken]) -> Result<()>;
+    /// Appends `token` to every sequence.
+    fn add_token(&mut self, token: LlamaToken) -> Result<()>;
+    /// Saves the tokens of every sequence along with their evaluated state.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+    /// Returns the sequences to the state saved in `snapshot`. Sequences that have only been
+    /// extended since then are returned to it without evaluating any tokens.
+    fn restore(&mut self, snapshot: &Self::Snapshot) -> Result<()>;
+
+    /// Saves the evaluated state of every sequence to the file at `path`, labelled with `key`, so
+    /// that [`Sequences::load_state`] can restore it.
+    fn save_state(&self, path: &Path, key: &[u8; 32]) -> Result<()>;
+    /// Sets the sequences to `tokens` using the state saved to the file at `path` by
+    /// [`Sequences::save_state`] for those same tokens. Returns `false` if the file does not hold
+    /// valid state labelled with `key`, in which case the tokens must be set again.
+    fn load_state(
+        &mut self,
+        path: &Path,
+        key: &[u8; 32],
+        tokens: &[Vec<LlamaToken>],
+    ) -> Result<bool>;
+
+    fn tokens(&self, seq: usize) -> &[LlamaToken];
+    fn get_token_data(&self, seq: usize) -> LlamaTokenDataArray;
+    fn model(&self) -> Self::Model;
+    /// The maximum number of tokens, including the BOS token, that each sequence can hold.
+    fn context_size(&self) -> usize;
+
+    fn set_prompt(&mut self, seq: usize, prompt: &str) -> Result<()> {
+        let mut tokens = self.model().str_to_token(prompt)?;
+        tokens.insert(0, self.model().token_bos());
+        self.set_tokens(seq, &tokens)
+    }
+    /// Sets the tokens of every sequence, in order.
+    fn set_all_tokens(&mut self, tokens: &[Vec<LlamaToken>]) -> Result<()> {
+        for (seq, tokens) in tokens.iter().enumerate() {
+            self.set_tokens(seq, tokens)?;
+        }
+        Ok(())
+    }
+}
+
+/// [`Sequences`] backed by a separate [`LanguageModel`] for each sequence.
+pub struct SeparateSequences<M>(pub Vec<M>);
+
+impl<M: LanguageModel> Sequences for SeparateSequences<M> {
+    type Model = M::Model;
+    type Snapshot = Vec<M::Snapshot>;
+
+    fn n_sequences(&self) -> usize {
+        self.0.len()
+    }
+
+    fn set_tokens(&mut self, seq: usize, tokens: &[LlamaToken]) -> Result<()> {
+        self.0[seq].set_tokens(tokens)
+    }
+
+    fn add_token(&mut self, token: LlamaToken) -> Result<()> {
+        for gen in &mut self.0 {
+            gen.add_token(token)?;
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Vec<M::Snapshot>> {
+        self.0.iter().map(LanguageModel::snapshot).collect()
+    }
+
+    fn restore(&mut self, snapshot: &Vec<M::Snapshot>) -> Result<()> {
+        for (gen, snapshot) in self.0.iter_mut().zip(snapshot) {
+            gen.restore(snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Separate models have no shared state to save, so only the key is saved, and loading it
+    /// evaluates the tokens again.
+    fn save_state(&self, path: &Path, key: &[u8; 32]) -> Result<()> {
+        Ok(std::fs::write(path, key)?)
+    }
+
+    fn load_state(
+        &mut self,
+        path: &Path,
+        key: &[u8; 32],
+        tokens: &[Vec<LlamaToken>],
+    ) -> Result<bool> {
+        if tokens.len() != self.0.len() || std::fs::read(path).ok().as_deref() != Some(key) {
+            return Ok(false);
+        }
+        self.set_all_tokens(tokens)?;
+        Ok(true)
+    }
+
+    fn tokens(&self, seq: usize) -> &[LlamaToken] {
+        self.0[seq].tokens()
+    }
+
+    fn get_token_data(&self, seq: usize) -> LlamaTokenDataArray {
+        self.0[seq].get_token_data()
+    }
+
+    fn model(&self) -> Self::Model {
+        self.0[0].model()
+    }
+
+    fn context_size(&self) -> usize {
+        self.0[0].context_size()
+    }
+}
+
+impl<'a> LanguageModel for GenerationContext<'a> {
+    type Model = &'a Model;
+    type Sequences = MultiGenerationContext<'a>;
+    type Snapshot = ContextSnapshot;
+
     fn partial_clone(&self) -> Result<Self> {
-        Self::new(self.context.model, self.params().clone())
+        Self::new(self.model, self.params().clone())
+    }
+
+    fn sequences(&self, n: usize, n_ctx: usize) -> Result<MultiGenerationContext<'a>> {
+        MultiGenerationContext::new(self.model, self.params().clone(), n, n_ctx)
     }
 
     fn add_tokens(&mut self, tokens: &[LlamaToken]) -> Result<()> {
@@ -144,6 +589,7 @@ impl LanguageModel for GenerationContext<'_> {
                 self.tokens.push(*token);
             }
             self.context.decode(&mut self.batch)?;
+            self.logits = self.context.get_logits_ith(chunk.len() as i32 - 1).to_vec();
         }
 
         Ok(())
@@ -157,29 +603,72 @@ impl LanguageModel for GenerationContext<'_> {
         Ok(())
     }
 
+    /// Copies the context's state, including the used part of its KV cache.
+    fn snapshot(&self) -> Result<ContextSnapshot> {
+        let mut state = vec![0; self.context.get_state_size()];
+        // SAFETY: `state` has room for the largest possible state of the context.
+        let len = unsafe { self.context.copy_state_data(state.as_mut_ptr()) };
+        state.truncate(len);
+
+        Ok(ContextSnapshot {
+            tokens: self.tokens.clone(),
+            logits: self.logits.clone(),
+            state,
+        })
+    }
+
+    /// Restores a context that has only been extended since the snapshot by truncating its KV
+    /// cache, and any other context by loading the snapshot's state.
+    fn restore(&mut self, snapshot: &ContextSnapshot) -> Result<()> {
+        if self.tokens.starts_with(&snapshot.tokens) {
+            self.truncate_tokens(snapshot.tokens.len())?;
+        } else {
+            // SAFETY: `snapshot.state` can only have been written by `copy_state_data`, and
+            // llama.cpp reads the state using the sizes recorded in it, so it reads no further
+            // than the end of what was written.
+            let read = unsafe { self.context.set_state_data(&snapshot.state) };
+            if read != snapshot.state.len() {
+                // The context may have been partially overwritten, so start over from nothing.
+                self.context.clear_kv_cache();
+                self.tokens.clear();
+                bail!(
+                    "Could not restore the context's state: read {read} of {} bytes",
+                    snapshot.state.len()
+                );
+            }
+            self.tokens.clone_from(&snapshot.tokens);
+        }
+        self.logits.clone_from(&snapshot.logits);
+
+        Ok(())
+    }
+
     fn tokens(&self) -> &[LlamaToken] {
         &self.tokens
     }
 
     fn get_token_data(&self) -> LlamaTokenDataArray {
-        LlamaTokenDataArray::from_iter(
-            self.context.candidates_ith(self.batch.n_tokens() - 1),
-            false,
-        )
+        token_data_from_logits(&self.logits)
+    }
+
+    fn model(&self) -> &'a Model {
+        self.model
     }
 
-    fn model(&self) -> &LlamaModel {
-        self.context.model
+    fn context_size(&self) -> usize {
+        self.context.n_ctx() as usize
     }
 }
 
 impl<'a> GenerationContext<'a> {
-    pub fn new(model: &'a LlamaModel, params: LlamaContextParams) -> Result<Self> {
+    pub fn new(model: &'a Model, params: LlamaContextParams) -> Result<Self> {
         let context = model.new_context(get_backend()?, params.clone())?;
 
         let mut out = Self {
+            model,
             context,
             tokens: Vec::new(),
+            logits: Vec::new(),
             batch: LlamaBatch::new(params.n_batch() as usize, 1),
             params,
         };
@@ -188,20 +677,6 @@ impl<'a> GenerationContext<'a> {
         Ok(out)
     }
 
-    pub fn add_tokens_get_token_data(
-        &mut self,
-        tokens: &[LlamaToken],
-    ) -> Result<Vec<LlamaTokenDataArray>> {
-        let mut out = vec![self.get_token_data()];
-
-        for token in tokens {
-            self.add_token(*token)?;
-            out.push(self.get_token_data());
-        }
-
-        Ok(out)
-    }
-
     pub fn embeddings(&self) -> Result<&[f32]> {
         self.context
             .embeddings_ith(self.batch.n_tokens() - 1)
@@ -212,17 +687,207 @@ impl<'a> GenerationContext<'a> {
         &self.params
     }
 
-    pub fn context(&self) -> &LlamaContext {
+    pub fn context(&self) -> &LlamaContext<'a> {
         &self.context
     }
+}
+
+/// [`Sequences`] that share a single llama.cpp context, using a separate sequence id for each.
+///
+/// Adding a token to every sequence takes only one batched decode, and the sequences share the
+/// context's KV cache and compute buffers.
+pub struct MultiGenerationContext<'a> {
+    model: &'a Model,
+    context: LlamaContext<'a>,
+    tokens: Vec<Vec<LlamaToken>>,
+    /// The logits after the last token of each sequence. They are copied out of the context after
+    /// every decode, since the next decode overwrites them.
+    logits: Vec<Vec<f32>>,
+    batch: LlamaBatch,
+    params: LlamaContextParams,
+}
 
-    pub fn model_longlived(&self) -> &'a LlamaModel {
-        self.context.model
+impl<'a> MultiGenerationContext<'a> {
+    /// Creates `n_seq` sequences, each of which can hold up to `n_ctx` tokens. The context is
+    /// otherwise created with `params`, and its size is the total of all of the sequences, since
+    /// llama.cpp gives each sequence an equal share of it.
+    pub fn new(
+        model: &'a Model,
+        params: LlamaContextParams,
+        n_seq: usize,
+        n_ctx: usize,
+    ) -> Result<Self> {
+        let params = params
+            .with_n_ctx(NonZeroU32::new((n_ctx * n_seq) as u32))
+            .with_n_seq_max(n_seq as u32);
+        let context = model.new_context(get_backend()?, params.clone())?;
+
+        let mut out = Self {
+            model,
+            context,
+            tokens: vec![Vec::new(); n_seq],
+            logits: vec![Vec::new(); n_seq],
+            batch: LlamaBatch::new((params.n_batch() as usize).max(n_seq), 1),
+            params,
+        };
+        for seq in 0..n_seq {
+            out.set_prompt(seq, "")?;
+        }
+
+        Ok(out)
+    }
+
+    pub fn params(&self) -> &LlamaContextParams {
+        &self.params
+    }
+
+    pub fn context(&self) -> &LlamaContext<'a> {
+        &self.context
+    }
+}
+
+impl<'a> Sequences for MultiGenerationContext<'a> {
+    type Model = &'a Model;
+    type Snapshot = SequencesSnapshot;
+
+    fn n_sequences(&self) -> usize {
+        self.tokens.len()
+    }
+
+    fn set_tokens(&mut self, seq: usize, new_tokens: &[LlamaToken]) -> Result<()> {
+        let n_tokens = reusable_tokens(&self.tokens[seq], new_tokens);
+
+        self.context
+            .clear_kv_cache_seq(Some(seq as u32), Some(n_tokens as u32), None)?;
+        self.tokens[seq].truncate(n_tokens);
+
+        for chunk in new_tokens[n_tokens..].chunks(self.params.n_batch() as usize) {
+            self.batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                self.batch.add(
+                    *token,
+                    self.tokens[seq].len() as i32,
+                    &[seq as i32],
+                    i == chunk.len() - 1,
+                )?;
+                self.tokens[seq].push(*token);
+            }
+            self.context.decode(&mut self.batch)?;
+            self.logits[seq] = self.context.get_logits_ith(chunk.len() as i32 - 1).to_vec();
+        }
+
+        Ok(())
+    }
+
+    fn add_token(&mut self, token: LlamaToken) -> Result<()> {
+        self.batch.clear();
+        for (seq, tokens) in self.tokens.iter_mut().enumerate() {
+            self.batch
+                .add(token, tokens.len() as i32, &[seq as i32], true)?;
+            tokens.push(token);
+        }
+        self.context.decode(&mut self.batch)?;
+
+        for (seq, logits) in self.logits.iter_mut().enumerate() {
+            *logits = self.context.get_logits_ith(seq as i32).to_vec();
+        }
+
+        Ok(())
+    }
+
+    /// Only copies the tokens and logits of each sequence, since restoring them only needs to
+    /// remove the tokens added since from the KV cache.
+    fn snapshot(&self) -> Result<SequencesSnapshot> {
+        Ok(SequencesSnapshot {
+            tokens: self.tokens.clone(),
+            logits: self.logits.clone(),
+        })
+    }
+
+    fn restore(&mut self, snapshot: &SequencesSnapshot) -> Result<()> {
+        for (seq, tokens) in snapshot.tokens.iter().enumerate() {
+            if self.tokens[seq].starts_with(tokens) {
+                self.context.clear_kv_cache_seq(
+                    Some(seq as u32),
+                    Some(tokens.len() as u32),
+                    None,
+                )?;
+                self.tokens[seq].truncate(tokens.len());
+            } else {
+                self.set_tokens(seq, tokens)?;
+            }
+        }
+        self.logits.clone_from(&snapshot.logits);
+
+        Ok(())
+    }
+
+    /// Saves the context's state as a llama.cpp session file, with the key in place of its
+    /// tokens.
+    fn save_state(&self, path: &Path, key: &[u8; 32]) -> Result<()> {
+        Ok(self.context.save_session_file(path, &key_tokens(key))?)
+    }
+
+    fn load_state(
+        &mut self,
+        path: &Path,
+        key: &[u8; 32],
+        tokens: &[Vec<LlamaToken>],
+    ) -> Result<bool> {
+        if tokens.len() != self.tokens.len() || tokens.iter().any(Vec::is_empty) {
+            return Ok(false);
+        }
+
+        // llama.cpp checks the size of every part of the file as it reads it, and fails if the
+        // file holds more tokens than the key.
+        let key = key_tokens(key);
+        if self.context.load_session_file(path, key.len()).ok() != Some(key) {
+            // The context may have been partially overwritten, so start over from empty sequences.
+            self.context.clear_kv_cache();
+            self.tokens.iter_mut().for_each(Vec::clear);
+            return Ok(false);
+        }
+        self.tokens = tokens.to_vec();
+
+        // The state only holds the logits of the last decode, so the last token of every sequence
+        // is evaluated again.
+        self.batch.clear();
+        for (seq, tokens) in self.tokens.iter().enumerate() {
+            let last = tokens.len() - 1;
+            self.context
+                .clear_kv_cache_seq(Some(seq as u32), Some(last as u32), None)?;
+            self.batch
+                .add(tokens[last], last as i32, &[seq as i32], true)?;
+        }
+        self.context.decode(&mut self.batch)?;
+
+        for (seq, logits) in self.logits.iter_mut().enumerate() {
+            *logits = self.context.get_logits_ith(seq as i32).to_vec();
+        }
+
+        Ok(true)
+    }
+
+    fn tokens(&self, seq: usize) -> &[LlamaToken] {
+        &self.tokens[seq]
+    }
+
+    fn get_token_data(&self, seq: usize) -> LlamaTokenDataArray {
+        token_data_from_logits(&self.logits[seq])
+    }
+
+    fn model(&self) -> &'a Model {
+        self.model
+    }
+
+    /// llama.cpp gives each sequence an equal share of the context.
+    fn context_size(&self) -> usize {
+        self.context.n_ctx() as usize / self.tokens.len()
     }
 }
 
 pub fn generate_tokens(
-    model: &LlamaModel,
+    model: impl Vocabulary,
     tokens: impl Iterator<Item = Result<LlamaToken>>,
 ) -> Result<Vec<LlamaToken>> {
     tokens
@@ -230,33 +895,269 @@ pub fn generate_tokens(
         .collect()
 }
 
+/// An iterator over the text of generated tokens, ending at the first end of generation token.
+///
+/// Each item contains the characters completed by one token, so it may be empty when a token ends
+/// partway through a character. An incomplete character at the end of the text is yielded as a
+/// [`char::REPLACEMENT_CHARACTER`].
+pub struct TextPieces<V, I> {
+    model: V,
+    tokens: I,
+    token_decoder: TokenDecoder,
+    done: bool,
+}
+
+impl<V: Vocabulary, I: Iterator<Item = Result<LlamaToken>>> TextPieces<V, I> {
+    pub fn new(model: V, tokens: I) -> Self {
+        Self {
+            model,
+            tokens,
+            token_decoder: TokenDecoder::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the text yielded so far.
+    pub fn text(&self) -> &str {
+        self.token_decoder.buffer()
+    }
+}
+
+impl<V: Vocabulary, I: Iterator<Item = Result<LlamaToken>>> Iterator for TextPieces<V, I> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let token = match self.tokens.next() {
+            Some(Ok(token)) if !self.model.is_eog_token(token) => token,
+            Some(Err(e)) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+            _ => {
+                self.done = true;
+                let last_part = self.token_decoder.last_part();
+                return (!last_part.is_empty()).then(|| Ok(last_part.to_string()));
+            }
+        };
+
+        Some(
+            self.model
+                .token_to_bytes(token)
+                .map(|bytes| self.token_decoder.add_token(&bytes).to_string()),
+        )
+    }
+}
+
+/// Collects the text of generated tokens, writing each piece of it to `out` as it is generated.
 pub fn generate_text(
-    model: &LlamaModel,
-    preview: bool,
+    model: impl Vocabulary,
+    out: &mut impl Write,
     tokens: impl Iterator<Item = Result<LlamaToken>>,
 ) -> Result<String> {
-    let mut token_decoder = TokenDecoder::new();
+    let mut text = String::new();
 
-    let tokens = tokens.map(|t| {
-        let Ok(t) = t else {
-            return t;
-        };
+    for piece in TextPieces::new(model, tokens) {
+        let piece = piece?;
+        out.write_all(piece.as_bytes())?;
+        out.flush()?;
+        text.push_str(&piece);
+    }
 
-        let piece = token_decoder.add_token(&model.token_to_bytes(t, Special::Tokenize)?);
+    Ok(text)
+}
 
-        if preview {
-            print!("{piece}");
-            std::io::Write::flush(&mut std::io::stdout())?;
-        }
+/// Checks the sequences of a [`MultiGenerationContext`] against separate contexts, and the
+/// decoder's sequences against the encoder's, using the model in the `LLAMA_TEST_MODEL`
+/// environment variable.
+#[test]
+#[ignore = "needs a GGUF model in LLAMA_TEST_MODEL"]
+fn test_multi_generation_context() {
+    use crate::{args::EncodeArgs, steganography::Steganography};
+
+    let path = std::env::var("LLAMA_TEST_MODEL").expect("LLAMA_TEST_MODEL should be set");
+    let model = load_model(path, false).unwrap();
+    let params = default_context_params().with_n_ctx(NonZeroU32::new(1024));
+    let mut gen = GenerationContext::new(&model, params).unwrap();
 
-        Ok(t)
-    });
+    let prompts = ["A prompt", "Another, longer prompt", ""].map(|p| gen.tokenize(p).unwrap());
+    let mut seqs = gen.sequences(prompts.len(), 256).unwrap();
+    seqs.set_all_tokens(&prompts).unwrap();
+    let continuation = model.str_to_token(" that goes on").unwrap();
+    for &token in &continuation {
+        seqs.add_token(token).unwrap();
+    }
 
-    generate_tokens(model, tokens)?;
+    let snapshot = seqs.snapshot().unwrap();
+    let token_data = seqs.get_token_data(0);
+    seqs.add_token(continuation[0]).unwrap();
+    seqs.restore(&snapshot).unwrap();
+    assert_eq!(seqs.get_token_data(0), token_data);
+
+    let argmax = |data: LlamaTokenDataArray| {
+        data.data
+            .iter()
+            .max_by(|a, b| a.logit().total_cmp(&b.logit()))
+            .map(LlamaTokenData::id)
+    };
+    for (seq, prompt) in prompts.iter().enumerate() {
+        let mut tokens = prompt.clone();
+        tokens.extend_from_slice(&continuation);
+        gen.set_tokens(&tokens).unwrap();
 
-    if preview {
-        print!("{}", token_decoder.last_part());
+        assert_eq!(seqs.tokens(seq), tokens);
+        assert_eq!(
+            argmax(seqs.get_token_data(seq)),
+            argmax(gen.get_token_data())
+        );
     }
 
-    Ok(token_decoder.into_string())
+    // Loading saved state gives the same predictions as evaluating the tokens.
+    let path = std::env::temp_dir().join(format!("session-{}", std::process::id()));
+    let tokens = (0..prompts.len())
+        .map(|seq| seqs.tokens(seq).to_vec())
+        .collect::<Vec<_>>();
+    seqs.save_state(&path, &[1; 32]).unwrap();
+    let mut loaded = gen.sequences(prompts.len(), 256).unwrap();
+    assert!(!loaded.load_state(&path, &[2; 32], &tokens).unwrap());
+    assert!(loaded.load_state(&path, &[1; 32], &tokens).unwrap());
+    for (seq, tokens) in tokens.iter().enumerate() {
+        assert_eq!(loaded.tokens(seq), tokens);
+        assert_eq!(
+            argmax(loaded.get_token_data(seq)),
+            argmax(seqs.get_token_data(seq))
+        );
+    }
+    std::fs::remove_file(path).unwrap();
+
+    // The decoder's sequences give exactly the logits that the encoder's did, though the writer's
+    // prompt is missing from them.
+    let mut args = EncodeArgs::new("Write a paragraph about range coding.");
+    args.token_count = 256;
+    let mut encoding = gen.encoding_sequences(&args, 64).unwrap();
+    let mut decoding = gen.decoding_sequences(&args.as_decode_args(), 64).unwrap();
+    for &token in &model.str_to_token(" Range coding hides bits.").unwrap() {
+        // The steganographer's and auxiliary sequences.
+        for seq in [1, 2] {
+            assert_eq!(encoding.get_token_data(seq), decoding.get_token_data(seq));
+        }
+        encoding.add_token(token).unwrap();
+        decoding.add_token(token).unwrap();
+    }
+
+    // So the message is recovered without robust mode.
+    let message = "This is a secret.";
+    let text = gen.encode_compressed(message, &args).unwrap();
+    assert_eq!(
+        gen.decode_compressed(&text, &args.as_decode_args())
+            .unwrap(),
+        message
+    );
+}
+
+/// Checks that a [`GenerationContext`] restored from a snapshot after its tokens have diverged,
+/// which loads the snapshot's state, predicts exactly what it did when the snapshot was taken,
+/// using the model in the `LLAMA_TEST_MODEL` environment variable.
+#[test]
+#[ignore = "needs a GGUF model in LLAMA_TEST_MODEL"]
+fn test_generation_context_snapshot() {
+    let path = std::env::var("LLAMA_TEST_MODEL").expect("LLAMA_TEST_MODEL should be set");
+    let model = load_model(path, false).unwrap();
+    let params = default_context_params().with_n_ctx(NonZeroU32::new(256));
+    let mut gen = GenerationContext::new(&model, params.clone()).unwrap();
+    gen.set_prompt("A prompt").unwrap();
+
+    let snapshot = gen.snapshot().unwrap();
+    let token_data = gen.get_token_data();
+
+    // Replace the last token, so that the snapshot's tokens are no longer a prefix of the
+    // context's and the KV cache can't simply be truncated.
+    let n_tokens = gen.tokens().len();
+    gen.truncate_tokens(n_tokens - 1).unwrap();
+    gen.add_tokens(&model.str_to_token(" different thing that goes on").unwrap())
+        .unwrap();
+    assert!(!gen.tokens().starts_with(&snapshot.tokens));
+    assert_ne!(gen.get_token_data(), token_data);
+
+    gen.restore(&snapshot).unwrap();
+    assert_eq!(gen.get_prompt().unwrap(), "A prompt");
+    assert_eq!(gen.get_token_data(), token_data);
+
+    // The KV cache was restored as well, so continuing gives the same logits as a context that
+    // evaluated the same tokens without diverging.
+    let mut fresh = GenerationContext::new(&model, params).unwrap();
+    fresh.set_prompt("A prompt").unwrap();
+    let continuation = model.str_to_token(" that goes on").unwrap();
+    gen.add_tokens(&continuation).unwrap();
+    fresh.add_tokens(&continuation).unwrap();
+    assert_eq!(gen.get_token_data(), fresh.get_token_data());
+}
+
+#[test]
+fn test_gguf_fingerprint() {
+    let path = std::env::temp_dir().join(format!("fingerprint-{}.gguf", std::process::id()));
+
+    let string = |s: &str| {
+        let mut bytes = (s.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(s.as_bytes());
+        bytes
+    };
+    let gguf = |name: &str, shape: u64| {
+        let mut file = b"GGUF".to_vec();
+        file.extend(3u32.to_le_bytes());
+        // One tensor and two metadata values.
+        file.extend(1u64.to_le_bytes());
+        file.extend(2u64.to_le_bytes());
+
+        file.extend(string("general.name"));
+        file.extend(GGUF_STRING.to_le_bytes());
+        file.extend(string(name));
+
+        file.extend(string("tokenizer.tokens"));
+        file.extend(GGUF_ARRAY.to_le_bytes());
+        file.extend(GGUF_STRING.to_le_bytes());
+        file.extend(2u64.to_le_bytes());
+        file.extend(string("a"));
+        file.extend(string("b"));
+
+        // The tensor's name, dimensions, type and offset.
+        file.extend(string("weights"));
+        file.extend(1u32.to_le_bytes());
+        file.extend(shape.to_le_bytes());
+        file.extend(0u32.to_le_bytes());
+        file.extend(0u64.to_le_bytes());
+
+        file.extend([0; 64]);
+        file
+    };
+    let fingerprint = |file: &[u8]| {
+        std::fs::write(&path, file).unwrap();
+        // Keep the modification time the same, so that only the contents are compared.
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        gguf_fingerprint(&path)
+    };
+
+    let file = gguf("model", 16);
+    let key = fingerprint(&file).unwrap();
+    assert_eq!(fingerprint(&file).unwrap(), key);
+
+    // The tensor data is not read.
+    let mut weights = file.clone();
+    *weights.last_mut().unwrap() = 1;
+    assert_eq!(fingerprint(&weights).unwrap(), key);
+
+    assert_ne!(fingerprint(&gguf("other", 16)).unwrap(), key);
+    assert_ne!(fingerprint(&gguf("model", 32)).unwrap(), key);
+    assert!(fingerprint(&file[..40]).is_err());
+    assert!(fingerprint(b"Not a model").is_err());
+
+    std::fs::remove_file(path).unwrap();
 }
@@ -0,0 +1,32 @@
+//! Hides and recovers secret messages within LLM-generated text.
+//!
+//! The main entry point is [`Stegano`], which wraps a loaded model and provides encoding,
+//! decoding, compression, and decompression of messages. Lower-level building blocks, such as the
+//! [`range_coder`] and the [`LanguageModel`](generation_context::LanguageModel) trait, are also
+//! exposed. The [`mock`] module provides a small synthetic model that can be used in place of a
+//! real one for testing.
+
+pub mod args;
+pub mod container;
+pub mod crypto;
+pub mod decoder;
+pub mod error;
+pub mod estimate;
+pub mod fec;
+pub mod generation_context;
+mod improved_utf8_chunks;
+pub mod mock;
+pub mod normalize;
+pub mod prompt_cache;
+pub mod range_coder;
+pub mod server;
+mod stegano;
+pub mod steganography;
+pub mod trace;
+
+pub use args::{DecodeArgs, EncodeArgs};
+pub use container::Payload;
+pub use error::{Error, Result};
+pub use estimate::Estimate;
+pub use generation_context::{default_context_params, load_model, Model};
+pub use stegano::Stegano;
@@ -1,23 +1,15 @@
-#![allow(dead_code)]
-
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+};
 
 use anyhow::Result;
-use clap::{Args, Parser, Subcommand};
-use generation_context::{get_backend, GenerationContext};
-use llama_cpp_2::{
-    context::params::LlamaContextParams,
-    model::{params::LlamaModelParams, LlamaModel},
+use clap::{ArgAction, Parser, Subcommand};
+use llama_cpp_steganography::{
+    load_model, server, trace::read_trace, DecodeArgs, EncodeArgs, Payload, Stegano,
 };
-
-use crate::range_coder::{bools_to_bytes, bytes_to_bools};
-
-mod decoder;
-mod generation_context;
-mod improved_utf8_chunks;
-mod logit_vector;
-mod range_coder;
-mod steganography;
+use log::{info, warn, LevelFilter};
+use serde_json::json;
 
 #[derive(Parser, Debug)]
 #[command(version, about, propagate_version = true)]
@@ -32,129 +24,296 @@ struct Cli {
     /// Run inference on the GPU
     #[arg(long, short)]
     gpu: bool,
-    // /// File to use as input (defaults to stdin)
-    // #[arg(short, long)]
-    // infile: Option<String>,
 
-    // /// File to use as output (defaults to stdout)
-    // #[arg(short, long)]
-    // outfile: Option<String>,
+    /// File to use as input (defaults to stdin)
+    #[arg(short, long)]
+    infile: Option<PathBuf>,
+
+    /// File to write the output to instead of stdout. The text generated by `encode` is still
+    /// previewed on stdout.
+    #[arg(short, long)]
+    outfile: Option<PathBuf>,
+
+    /// Write the result as a single JSON object, along with statistics about it, instead of the
+    /// plain output. The cover text is not previewed.
+    #[arg(long)]
+    json: bool,
+
+    /// Log more details to stderr. Can be given up to three times.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Command {
-    /// Hide the message sent into stdin in the generated text
-    Encode(EncodeArgs),
+    /// Hide the message read from the input in the generated text
+    Encode {
+        #[command(flatten)]
+        args: EncodeArgs,
+
+        /// Hide the message as raw bytes instead of compressing it as text. This is done
+        /// automatically when the message is not valid UTF-8.
+        #[arg(long)]
+        binary: bool,
+
+        /// Save the settings needed to decode the message to this file
+        #[arg(long)]
+        save_settings: Option<PathBuf>,
+    },
+
+    /// Recover a hidden message from the text read from the input
+    Decode {
+        #[command(flatten)]
+        args: DecodeArgs,
+
+        /// Load the decoding settings from a file written by `encode --save-settings`, ignoring
+        /// any settings given on the command line other than the key and trace file
+        #[arg(long)]
+        settings: Option<PathBuf>,
+    },
+
+    /// Decode the text read from the input, and report the first token where the decoder's
+    /// decisions differ from those in a trace written by `encode --trace`
+    DiffDecode {
+        #[command(flatten)]
+        args: DecodeArgs,
+
+        /// Load the decoding settings from a file written by `encode --save-settings`, as
+        /// `decode --settings` does
+        #[arg(long)]
+        settings: Option<PathBuf>,
+
+        /// The trace written by `encode --trace` when the text was generated
+        #[arg(long)]
+        encoder_trace: PathBuf,
+    },
+
+    /// Estimate the number of tokens needed to hide the message read from the input
+    Estimate {
+        #[command(flatten)]
+        args: EncodeArgs,
 
-    /// Recover a hidden message from the text sent to stdin
-    Decode(DecodeArgs),
+        /// Estimate for hiding the message as raw bytes, as `encode --binary` does
+        #[arg(long)]
+        binary: bool,
+
+        /// The number of tokens to generate while measuring how many bits each token hides
+        #[arg(long, default_value_t = 256)]
+        sample_tokens: usize,
+    },
 
     /// Use the model to compress a file
     Compress,
 
     /// Use the model to decompress a file compressed by this program
     Decompress,
-}
 
-#[derive(Args, Debug)]
-#[command(version, about)]
-struct EncodeArgs {
-    /// The user prompt for the generated text
-    prompt: String,
+    /// Keep the model loaded and serve encoding, decoding, and compression over HTTP
+    Serve {
+        /// The address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: String,
+    },
+}
 
-    /// The number of tokens to skip at the start of the generation before starting to encode the
-    /// message
-    #[arg(short = 'k', long, default_value_t = 8)]
-    skip_start: usize,
+fn read_input(infile: &Option<PathBuf>) -> Result<Vec<u8>> {
+    match infile {
+        Some(path) => Ok(std::fs::read(path)?),
+        None => {
+            let mut input = Vec::new();
+            std::io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+    }
+}
 
-    /// The maximum number of tokens to generate
-    #[arg(short, long, default_value_t = 4096)]
-    token_count: usize,
+/// Writes `output` to the output file. Output that was not already previewed on stdout is written
+/// there when no output file is given.
+fn write_output(outfile: &Option<PathBuf>, output: &[u8], previewed: bool) -> Result<()> {
+    match outfile {
+        Some(path) => std::fs::write(path, output)?,
+        None if !previewed => std::io::stdout().write_all(output)?,
+        None => {}
+    }
+    Ok(())
+}
 
-    /// MinP filtering value for sampling
-    #[arg(long, default_value_t = 0.02)]
-    min_p: f32,
+/// Opens the destination of output that is streamed as it is produced: the output file, or stdout
+/// when no output file is given. Nothing is streamed when the result is written as JSON.
+fn stream_output(outfile: &Option<PathBuf>, json: bool) -> Result<Box<dyn Write>> {
+    Ok(match outfile {
+        _ if json => Box::new(std::io::sink()),
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    })
+}
 
-    /// TopK filtering value for sampling
-    #[arg(long, default_value_t = 0)]
-    top_k: usize,
+fn init_logging(args: &Cli) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
 
-    /// Temperature sampling value
-    #[arg(long, default_value_t = 1.0)]
-    temp: f32,
+    // `RUST_LOG` overrides the level for the modules it names.
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format_timestamp(None)
+        .init();
 }
 
-#[derive(Args, Debug)]
-#[command(version, about)]
-struct DecodeArgs {
-    /// The number of tokens to skip at the start of the generation before starting to encode the
-    /// message
-    #[arg(short = 'k', long, default_value_t = 8)]
-    skip_start: usize,
-
-    /// MinP filtering value for sampling
-    #[arg(long, default_value_t = 0.02)]
-    min_p: f32,
-
-    /// TopK filtering value for sampling
-    #[arg(long, default_value_t = 0)]
-    top_k: usize,
-
-    /// Temperature sampling value
-    #[arg(long, default_value_t = 1.0)]
-    temp: f32,
+/// Replaces the settings in `args` with those saved in the settings file, if one is given.
+fn load_settings(args: DecodeArgs, settings: Option<PathBuf>) -> Result<DecodeArgs> {
+    Ok(match settings {
+        Some(path) => DecodeArgs {
+            key: args.key,
+            trace: args.trace,
+            ..serde_json::from_str(&std::fs::read_to_string(path)?)?
+        },
+        None => args,
+    })
 }
 
-impl EncodeArgs {
-    fn as_decode_args(&self) -> DecodeArgs {
-        DecodeArgs {
-            skip_start: self.skip_start,
-            min_p: self.min_p,
-            top_k: self.top_k,
-            temp: self.temp,
-        }
-    }
+/// Formats a JSON result as a single line.
+fn json_line(value: serde_json::Value) -> Vec<u8> {
+    format!("{value}\n").into_bytes()
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
+    init_logging(&args);
 
-    let model = LlamaModel::load_from_file(
-        get_backend()?,
-        &args.model,
-        &LlamaModelParams::default().with_n_gpu_layers(args.gpu as u32 * 1000),
-    )?;
-    let params = LlamaContextParams::default()
-        .with_n_ctx(std::num::NonZeroU32::new(8192))
-        .with_n_threads(4)
-        .with_n_threads_batch(8)
-        .with_n_batch(2048);
-
-    let mut gen = GenerationContext::new(&model, params.clone())?;
+    let model = load_model(&args.model, args.gpu)?;
+    let mut stegano = Stegano::new(&model)?;
 
     match args.command {
-        Command::Encode(encode_args) => {
-            let input = std::io::read_to_string(std::io::stdin())?;
-            gen.encode_compressed(&input, &encode_args)?;
+        Command::Encode {
+            args: encode_args,
+            binary,
+            save_settings,
+        } => {
+            let input = read_input(&args.infile)?;
+            if let Some(path) = save_settings {
+                std::fs::write(
+                    path,
+                    serde_json::to_string_pretty(&encode_args.as_decode_args())?,
+                )?;
+            }
+            let (mut stdout, mut sink) = (std::io::stdout(), std::io::sink());
+            let mut preview: &mut dyn Write = if args.json { &mut sink } else { &mut stdout };
+            let report = match std::str::from_utf8(&input) {
+                Ok(text) if !binary => stegano.encode_report(text, &encode_args, &mut preview)?,
+                _ => stegano.encode_bytes_report(&input, &encode_args, &mut preview)?,
+            };
+            info!(
+                "Hid {} bits in {} tokens ({:.3} bits per token)",
+                report.hidden_bits,
+                report.tokens.len(),
+                report.bits_per_token()
+            );
+            if args.json {
+                write_output(&args.outfile, &json_line(report.to_json()), false)?;
+            } else {
+                write_output(&args.outfile, report.text.as_bytes(), true)?;
+            }
         }
-        Command::Decode(decode_args) => {
-            let input = std::io::read_to_string(std::io::stdin())?;
-            gen.decode_compressed(&input, &decode_args)?;
+        Command::Decode {
+            args: decode_args,
+            settings,
+        } => {
+            let decode_args = load_settings(decode_args, settings)?;
+            let input = String::from_utf8(read_input(&args.infile)?)?;
+            let mut stream = stream_output(&args.outfile, args.json)?;
+            let payload = stegano.decode_payload_to(&input, &decode_args, &mut stream)?;
+            match payload {
+                _ if args.json => {
+                    write_output(&args.outfile, &json_line(payload.to_json()), false)?
+                }
+                // Decompressed text has already been streamed.
+                Payload::Text(_) => {}
+                Payload::Bytes(bytes) => stream.write_all(&bytes)?,
+            }
+        }
+        Command::DiffDecode {
+            args: decode_args,
+            settings,
+            encoder_trace,
+        } => {
+            let decode_args = load_settings(decode_args, settings)?;
+            let encoder_trace = read_trace(&encoder_trace)?;
+            let input = String::from_utf8(read_input(&args.infile)?)?;
+            let divergence = stegano.diff_decode(&input, &decode_args, &encoder_trace)?;
+            let output = match (args.json, divergence) {
+                (true, divergence) => json_line(json!({ "divergence": divergence })),
+                (false, Some(divergence)) => format!("{divergence}\n").into_bytes(),
+                (false, None) => {
+                    format!("The traces agree on all {} tokens.\n", encoder_trace.len())
+                        .into_bytes()
+                }
+            };
+            write_output(&args.outfile, &output, false)?;
+        }
+        Command::Estimate {
+            args: encode_args,
+            binary,
+            sample_tokens,
+        } => {
+            let input = read_input(&args.infile)?;
+            let estimate = match std::str::from_utf8(&input) {
+                Ok(text) if !binary => stegano.estimate(text, &encode_args, sample_tokens)?,
+                _ => stegano.estimate_bytes(&input, &encode_args, sample_tokens)?,
+            };
+            if estimate.token_bounds().1 > encode_args.token_count as f64 {
+                warn!(
+                    "The message may not fit in {} tokens. Consider raising --token-count.",
+                    encode_args.token_count
+                );
+            }
+            let output = if args.json {
+                json_line(estimate.to_json())
+            } else {
+                format!("{estimate}\n").into_bytes()
+            };
+            write_output(&args.outfile, &output, false)?;
         }
         Command::Compress => {
-            let input = std::io::read_to_string(std::io::stdin())?;
-            eprintln!("Normal: {} bytes", input.len());
-            let compressed = bools_to_bytes(&gen.compress_message(&input)?);
-            eprintln!("Compressed: {} bytes", compressed.len());
-            std::io::stdout().write_all(&compressed)?;
+            let input = String::from_utf8(read_input(&args.infile)?)?;
+            info!("Normal: {} bytes", input.len());
+            let compressed = stegano.compress(&input)?;
+            info!("Compressed: {} bytes", compressed.len());
+            let output = if args.json {
+                json_line(json!({
+                    "compressed": compressed,
+                    "message_bytes": input.len(),
+                    "compressed_bytes": compressed.len(),
+                    "compression_ratio": compressed.len() as f64 / input.len().max(1) as f64,
+                }))
+            } else {
+                compressed
+            };
+            write_output(&args.outfile, &output, false)?;
         }
         Command::Decompress => {
-            let mut input = Vec::new();
-            std::io::stdin().read_to_end(&mut input)?;
-            eprintln!("Compressed: {} bytes", input.len());
-            let decompressed = gen.decompress_message(bytes_to_bools(&input, None))?;
-            eprintln!("Normal: {} bytes", decompressed.len());
+            let input = read_input(&args.infile)?;
+            info!("Compressed: {} bytes", input.len());
+            let mut stream = stream_output(&args.outfile, args.json)?;
+            let decompressed = stegano.decompress_to(&input, &mut stream)?;
+            info!("Normal: {} bytes", decompressed.len());
+            if args.json {
+                write_output(
+                    &args.outfile,
+                    &json_line(json!({ "message": decompressed })),
+                    false,
+                )?;
+            }
         }
+        Command::Serve { address } => server::serve(&mut stegano, &address)?,
     }
 
     Ok(())
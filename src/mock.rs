@@ -0,0 +1,285 @@
+//! A deterministic synthetic language model, used to exercise the steganography pipeline without
+//! loading a GGUF file.
+
+use anyhow::Result;
+use llama_cpp_2::token::{data::LlamaTokenData, data_array::LlamaTokenDataArray, LlamaToken};
+
+use crate::generation_context::{LanguageModel, SeparateSequences, Vocabulary};
+
+const BOS: LlamaToken = LlamaToken(256);
+const EOS: LlamaToken = LlamaToken(257);
+const N_VOCAB: i32 = 258;
+/// Strings of bytes that [`MockModel::with_merges`] adds as tokens after the EOS token.
+const MERGES: [&[u8]; 9] = [
+    b"th", b"he", b"in", b"er", b"an", b" t", b"e ", b". ", b"ion",
+];
+
+/// The logits of printable tokens are spread evenly over `0.0..SPREAD`.
+const SPREAD: f32 = 6.0;
+const UNPRINTABLE_LOGIT: f32 = -30.0;
+
+/// A byte-level model whose logits are a hash of a seed and the last few tokens of the context.
+///
+/// Tokens `0..256` are single bytes, followed by a BOS and an EOS token. Printable ASCII is
+/// strongly preferred, so generated text survives being detokenized and tokenized again. Tokens
+/// for longer strings can be added with [`MockModel::with_merges`], so that text can be generated
+/// that tokenizes differently.
+#[derive(Clone, Copy, Debug)]
+pub struct MockModel {
+    seed: u64,
+    order: usize,
+    eos_logit: f32,
+    merges: bool,
+    noise: f32,
+}
+
+/// A [`LanguageModel`] that evaluates a [`MockModel`].
+#[derive(Clone, Debug)]
+pub struct MockContext<'a> {
+    model: &'a MockModel,
+    tokens: Vec<LlamaToken>,
+    context_size: usize,
+}
+
+fn mix(mut x: u64) -> u64 {
+    // SplitMix64 finalizer
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn is_printable(token: LlamaToken) -> bool {
+    matches!(token.0, 0x20..=0x7e) || token.0 == b'\n' as i32
+}
+
+impl MockModel {
+    /// Creates a model whose predictions depend on the last two tokens.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            order: 2,
+            eos_logit: 0.0,
+            merges: false,
+            noise: 0.0,
+        }
+    }
+
+    /// Sets the number of previous tokens that the model's predictions depend on.
+    pub fn with_order(mut self, order: usize) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets the logit of the EOS token. By default, it is less likely than most printable tokens.
+    pub fn with_eos_logit(mut self, eos_logit: f32) -> Self {
+        self.eos_logit = eos_logit;
+        self
+    }
+
+    /// Adds tokens for a few common pairs of printable bytes, which are preferred over single bytes
+    /// when tokenizing. `ion` is also added, though none of its pairs are, so that text can
+    /// tokenize differently even though every pair of its tokens tokenizes the same way.
+    pub fn with_merges(mut self) -> Self {
+        self.merges = true;
+        self
+    }
+
+    /// Adds a different offset of at most `noise` to every logit, like the tiny numerical
+    /// differences between machines running the same model.
+    pub fn with_noise(mut self, noise: f32) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    fn n_vocab(&self) -> i32 {
+        if self.merges {
+            N_VOCAB + MERGES.len() as i32
+        } else {
+            N_VOCAB
+        }
+    }
+
+    fn logit(&self, context_hash: u64, token: LlamaToken) -> f32 {
+        if token == EOS {
+            self.eos_logit
+        } else if is_printable(token) || token.0 >= N_VOCAB {
+            let x = mix(context_hash ^ token.0 as u64);
+            (x >> 40) as f32 / (1u64 << 24) as f32 * SPREAD
+        } else {
+            UNPRINTABLE_LOGIT
+        }
+    }
+
+    fn noise(&self, context_hash: u64, token: LlamaToken) -> f32 {
+        let x = mix(!context_hash ^ token.0 as u64);
+        ((x >> 40) as f32 / (1u64 << 23) as f32 - 1.0) * self.noise
+    }
+
+    fn token_data(&self, context: &[LlamaToken]) -> LlamaTokenDataArray {
+        let context_hash = context[context.len().saturating_sub(self.order)..]
+            .iter()
+            .fold(mix(self.seed), |h, t| mix(h ^ t.0 as u64));
+
+        LlamaTokenDataArray::from_iter(
+            (0..self.n_vocab()).map(|i| {
+                let token = LlamaToken(i);
+                let logit = self.logit(context_hash, token) + self.noise(context_hash, token);
+                LlamaTokenData::new(token, logit, 0.)
+            }),
+            false,
+        )
+    }
+}
+
+impl Vocabulary for MockModel {
+    fn token_bos(&self) -> LlamaToken {
+        BOS
+    }
+
+    fn token_eos(&self) -> LlamaToken {
+        EOS
+    }
+
+    fn is_eog_token(&self, token: LlamaToken) -> bool {
+        token == EOS
+    }
+
+    fn token_to_bytes(&self, token: LlamaToken) -> Result<Vec<u8>> {
+        let merge = usize::try_from(token.0 - N_VOCAB)
+            .ok()
+            .and_then(|i| MERGES.get(i))
+            .filter(|_| self.merges);
+
+        Ok(match merge {
+            Some(merge) => merge.to_vec(),
+            None => u8::try_from(token.0).map(|b| vec![b]).unwrap_or_default(),
+        })
+    }
+
+    fn str_to_token(&self, text: &str) -> Result<Vec<LlamaToken>> {
+        let mut bytes = text.as_bytes();
+        let mut tokens = Vec::with_capacity(bytes.len());
+
+        while let Some(&b) = bytes.first() {
+            let merge = MERGES
+                .iter()
+                .position(|merge| bytes.starts_with(merge))
+                .filter(|_| self.merges);
+
+            match merge {
+                Some(i) => {
+                    tokens.push(LlamaToken(N_VOCAB + i as i32));
+                    bytes = &bytes[MERGES[i].len()..];
+                }
+                None => {
+                    tokens.push(LlamaToken(b as i32));
+                    bytes = &bytes[1..];
+                }
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    fn apply_chat_template(&self, prompt: &str) -> Result<String> {
+        Ok(format!("<|user|>\n{prompt}\n<|assistant|>\n"))
+    }
+
+    fn fingerprint(&self) -> Result<[u8; 32]> {
+        let hash = mix(self.seed ^ mix(self.order as u64 ^ mix(self.eos_logit.to_bits() as u64)))
+            ^ mix(self.noise.to_bits() as u64)
+            ^ self.merges as u64;
+
+        let mut fingerprint = [0; 32];
+        fingerprint[..8].copy_from_slice(&hash.to_le_bytes());
+        Ok(fingerprint)
+    }
+}
+
+impl<'a> MockContext<'a> {
+    pub fn new(model: &'a MockModel) -> Self {
+        Self {
+            model,
+            tokens: vec![BOS],
+            context_size: usize::MAX,
+        }
+    }
+
+    /// Limits the number of tokens that the context can hold. By default, it is unlimited.
+    pub fn with_context_size(mut self, context_size: usize) -> Self {
+        self.context_size = context_size;
+        self
+    }
+}
+
+impl<'a> LanguageModel for MockContext<'a> {
+    type Model = &'a MockModel;
+    type Sequences = SeparateSequences<Self>;
+    type Snapshot = Vec<LlamaToken>;
+
+    fn partial_clone(&self) -> Result<Self> {
+        Ok(Self::new(self.model).with_context_size(self.context_size))
+    }
+
+    fn sequences(&self, n: usize, n_ctx: usize) -> Result<SeparateSequences<Self>> {
+        Ok(SeparateSequences(
+            (0..n)
+                .map(|_| Ok(self.partial_clone()?.with_context_size(n_ctx)))
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    fn add_tokens(&mut self, tokens: &[LlamaToken]) -> Result<()> {
+        self.tokens.extend_from_slice(tokens);
+        Ok(())
+    }
+
+    fn truncate_tokens(&mut self, length: usize) -> Result<()> {
+        self.tokens.truncate(length);
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Vec<LlamaToken>> {
+        Ok(self.tokens.clone())
+    }
+
+    fn restore(&mut self, snapshot: &Vec<LlamaToken>) -> Result<()> {
+        self.tokens.clone_from(snapshot);
+        Ok(())
+    }
+
+    fn tokens(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    fn get_token_data(&self) -> LlamaTokenDataArray {
+        self.model.token_data(&self.tokens)
+    }
+
+    fn model(&self) -> &'a MockModel {
+        self.model
+    }
+
+    fn context_size(&self) -> usize {
+        self.context_size
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let model = MockModel::new(0);
+    let mut gen = MockContext::new(&model);
+    gen.set_prompt("A prompt").unwrap();
+
+    let snapshot = gen.snapshot().unwrap();
+    let token_data = gen.get_token_data();
+
+    gen.add_tokens(&model.str_to_token(" that goes on").unwrap())
+        .unwrap();
+    assert_ne!(gen.get_token_data(), token_data);
+
+    gen.restore(&snapshot).unwrap();
+    assert_eq!(gen.get_prompt().unwrap(), "A prompt");
+    assert_eq!(gen.get_token_data(), token_data);
+}
@@ -0,0 +1,224 @@
+//! Keeping the cover text recoverable after it is tokenized again or lightly edited.
+//!
+//! The decoder only sees the text, so it must tokenize it into exactly the tokens that the encoder
+//! chose. Tokenizers do not always split text the way it was generated, so the encoder only
+//! chooses tokens that [`is_stable`] accepts: tokens that can be appended to the text without
+//! changing how the text so far is tokenized.
+//!
+//! The text must also survive common edits, such as trimming trailing whitespace or reflowing
+//! Markdown paragraphs. The decoder undoes these by passing the text through [`normalize`], and
+//! [`is_stable`] only accepts tokens that keep the generated text unchanged by it, so normalizing
+//! the unedited text does nothing. Normalization:
+//!
+//! - removes carriage returns,
+//! - removes whitespace at the ends of lines,
+//! - collapses runs of spaces within a line, after its indentation, into a single space, and
+//! - joins lines that continue a paragraph with a space, as Markdown renders them. A line
+//!   continues a paragraph if it follows a non-blank line and does not start with whitespace, a
+//!   digit, or one of `-*+#>|` and backtick, which could start a list, heading, quote, table, or
+//!   code block.
+
+use anyhow::Result;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::generation_context::Vocabulary;
+
+/// The number of preceding tokens of the text that are checked for edits that normalization
+/// would undo.
+const TAIL_TOKENS: usize = 16;
+
+/// Returns whether a line starting with `c` may start a new Markdown block, rather than continuing
+/// a paragraph.
+fn starts_block(c: char) -> bool {
+    c.is_ascii_digit() || c.is_whitespace() || "-*+#>|`".contains(c)
+}
+
+/// Collapses runs of spaces after the indentation of `line`.
+fn collapse_spaces(line: &str) -> String {
+    let content = line.trim_start();
+    let mut out = line[..line.len() - content.len()].to_string();
+
+    // The content starts with a non-space character, so this never collapses the indentation.
+    for c in content.chars() {
+        if !(c == ' ' && out.ends_with(' ')) {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+/// Undoes common edits to the cover text. Text chosen by the encoder is never changed, except for
+/// whitespace at its very end.
+pub fn normalize(text: &str) -> String {
+    let text = text.replace('\r', "");
+    let mut out = String::with_capacity(text.len());
+    let mut previous_blank = true;
+
+    for (i, line) in text.split('\n').enumerate() {
+        let line = collapse_spaces(line.trim_end_matches([' ', '\t']));
+
+        if i > 0 {
+            let continues =
+                !previous_blank && line.chars().next().is_some_and(|c| !starts_block(c));
+            out.push(if continues { ' ' } else { '\n' });
+        }
+        previous_blank = line.is_empty();
+        out.push_str(&line);
+    }
+
+    out
+}
+
+/// Returns whether appending `piece` to text ending in `tail` leaves the text unchanged by
+/// [`normalize`], apart from whitespace at its end.
+fn normalizes_to_itself(tail: &str, piece: &str) -> bool {
+    let mut chars = tail.chars().collect::<Vec<_>>();
+
+    for c in piece.chars() {
+        let line_start = chars.iter().rposition(|&c| c == '\n').map_or(0, |i| i + 1);
+        let line = &chars[line_start..];
+        let previous = chars.last().copied();
+
+        let edited = match c {
+            '\r' => true,
+            '\n' => matches!(previous, Some(' ' | '\t')),
+            ' ' => previous == Some(' ') && line.iter().any(|c| !c.is_whitespace()),
+            // Starting a line that continues a paragraph.
+            c if previous == Some('\n') => {
+                chars.len() >= 2 && chars[chars.len() - 2] != '\n' && !starts_block(c)
+            }
+            _ => false,
+        };
+        if edited {
+            return false;
+        }
+        chars.push(c);
+    }
+
+    true
+}
+
+fn check_stable(model: impl Vocabulary, context: &[LlamaToken], token: LlamaToken) -> Result<bool> {
+    let piece = String::from_utf8(model.token_to_bytes(token)?)?;
+
+    let mut tail = Vec::new();
+    for &token in &context[context.len().saturating_sub(TAIL_TOKENS)..] {
+        tail.extend(model.token_to_bytes(token)?);
+    }
+    if !normalizes_to_itself(&String::from_utf8_lossy(&tail), &piece) {
+        return Ok(false);
+    }
+
+    // If the previous token and this one tokenize differently together, the text does too.
+    let previous = match context.last() {
+        Some(&previous) => String::from_utf8(model.token_to_bytes(previous)?)?,
+        None => String::new(),
+    };
+    let mut expected = model.str_to_token(&previous)?;
+    expected.push(token);
+
+    Ok(model.str_to_token(&(previous + &piece))? == expected)
+}
+
+/// Returns the index of the first token where tokenizing `text` differs from `tokens`, if any.
+pub fn retokenization_mismatch(
+    model: impl Vocabulary,
+    text: &str,
+    tokens: &[LlamaToken],
+) -> Result<Option<usize>> {
+    let retokenized = model.str_to_token(text)?;
+
+    Ok(tokens
+        .iter()
+        .zip(&retokenized)
+        .position(|(a, b)| a != b)
+        .or((tokens.len() != retokenized.len()).then(|| tokens.len().min(retokenized.len()))))
+}
+
+/// Returns whether `token` can follow the tokens of the text so far, `context`, such that
+/// tokenizing the text again gives the same tokens and [`normalize`] leaves it unchanged. Tokens
+/// that are not valid UTF-8 on their own are never stable.
+///
+/// Only the previous token is considered when checking the tokenization, which is exact for
+/// tokenizers that never merge across more than two tokens.
+pub fn is_stable(model: impl Vocabulary, context: &[LlamaToken], token: LlamaToken) -> bool {
+    check_stable(model, context, token).unwrap_or(false)
+}
+
+#[test]
+fn test_normalize() {
+    assert_eq!(
+        normalize("A paragraph  with  spaces.\n    - An indented  item\n"),
+        "A paragraph with spaces.\n    - An indented item\n"
+    );
+
+    let text = "# Title\n\nA paragraph with text.\n  - An indented item\n\n1. First";
+    assert_eq!(normalize(text), text);
+    assert_eq!(normalize(&text.replace('\n', " \t\r\n")), text);
+    assert_eq!(
+        normalize(&text.replace("paragraph with", "paragraph\nwith")),
+        text
+    );
+    assert_eq!(normalize(&format!("{text}  \n")), format!("{text}\n"));
+
+    for (tail, piece, stable) in [
+        ("A line", " more", true),
+        ("A line", "  more", false),
+        ("A line ", " more", false),
+        ("    ", " indented", true),
+        ("A line", "\n\nNext", true),
+        ("A line", " \n", false),
+        ("A line\n", "continued", false),
+        ("A line\n", "- item", true),
+        ("", "Start", true),
+        ("\n", "Start", true),
+        ("A line", "\r\n", false),
+    ] {
+        assert_eq!(
+            normalizes_to_itself(tail, piece),
+            stable,
+            "{tail:?} {piece:?}"
+        );
+    }
+}
+
+#[test]
+fn test_is_stable() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0).with_merges();
+    let tokens = |text: &str| model.str_to_token(text).unwrap();
+    let th = tokens("th");
+    assert_eq!(th.len(), 1);
+    let th = th[0];
+
+    assert!(is_stable(model, &[], th));
+    assert!(is_stable(model, &tokens("Say"), th));
+    // "t" followed by "h" is tokenized as "th".
+    assert!(!is_stable(model, &tokens("t"), tokens("h")[0]));
+    // "t" followed by "he" is tokenized as "th" followed by "e".
+    assert!(!is_stable(model, &tokens("t"), tokens("he")[0]));
+    assert!(is_stable(model, &tokens("word"), tokens(" ")[0]));
+    // "e" followed by " " is tokenized as "e ".
+    assert!(!is_stable(model, &tokens("the"), tokens(" ")[0]));
+    assert!(!is_stable(model, &tokens("A line\n"), tokens("c")[0]));
+
+    // Each pair of tokens in "ion" is stable, but the three together are not.
+    let ion = [tokens("i")[0], tokens("o")[0], tokens("n")[0]];
+    assert!(is_stable(model, &ion[..1], ion[1]) && is_stable(model, &ion[..2], ion[2]));
+    let mut text = tokens("Nat");
+    text.extend(ion);
+    assert_eq!(
+        retokenization_mismatch(model, "Nation", &text).unwrap(),
+        Some(3)
+    );
+    assert_eq!(
+        retokenization_mismatch(model, "Nation", &tokens("Nation")).unwrap(),
+        None
+    );
+    assert_eq!(
+        retokenization_mismatch(model, "Nation", &tokens("Natio")).unwrap(),
+        Some(3)
+    );
+}
@@ -0,0 +1,109 @@
+//! Caching evaluated prompts on disk.
+//!
+//! Encoding with the same prompts many times spends much of its time evaluating them. A prompt
+//! cache file stores the evaluated state of every [`Sequences`] after their prompts were set,
+//! written by [`Sequences::save_state`]. For a llama.cpp context, this is a llama.cpp session file.
+//!
+//! The state is labelled with a key, the BLAKE2b hash of the model's
+//! [fingerprint](Vocabulary::fingerprint), the [size](Sequences::context_size) of the sequences,
+//! and the tokens of the prompts. Encoding sizes the sequences from the number of tokens to
+//! generate, so a cache saved with a different `--token-count` is not loaded either. A file with a different
+//! key, or that cannot be loaded, is ignored and overwritten. The file is replaced by renaming a
+//! complete new file over it, so an interrupted run never leaves a partially written file behind.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use blake2::Digest;
+use llama_cpp_2::token::LlamaToken;
+
+use crate::generation_context::{Blake2b256, Sequences, Vocabulary};
+
+fn cache_key(
+    model: impl Vocabulary,
+    context_size: usize,
+    tokens: &[Vec<LlamaToken>],
+) -> Result<[u8; 32]> {
+    let mut hasher = Blake2b256::new();
+    hasher.update(model.fingerprint()?);
+    hasher.update((context_size as u64).to_le_bytes());
+
+    for tokens in tokens {
+        hasher.update((tokens.len() as u64).to_le_bytes());
+        tokens.iter().for_each(|t| hasher.update(t.0.to_le_bytes()));
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Sets the tokens of every sequence, loading their state from the cache file at `path` if it was
+/// saved for the same model and tokens. Otherwise, the tokens are evaluated and the cache file is
+/// replaced with their state.
+pub fn set_tokens_cached(
+    seqs: &mut impl Sequences,
+    tokens: &[Vec<LlamaToken>],
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let path = path.as_ref();
+    let key = cache_key(seqs.model(), seqs.context_size(), tokens)?;
+
+    if seqs.load_state(path, &key, tokens)? {
+        return Ok(());
+    }
+    seqs.set_all_tokens(tokens)?;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let temp = PathBuf::from(temp);
+
+    let saved = seqs.save_state(&temp, &key);
+    let result = saved.and_then(|()| Ok(std::fs::rename(&temp, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+#[test]
+fn test_prompt_cache() {
+    use crate::{
+        generation_context::LanguageModel,
+        mock::{MockContext, MockModel},
+    };
+
+    let path = std::env::temp_dir().join(format!("prompt-cache-{}", std::process::id()));
+    let model = MockModel::new(0);
+    let gen = MockContext::new(&model);
+    let tokens = vec![
+        gen.tokenize("A prompt").unwrap(),
+        gen.tokenize("Another prompt").unwrap(),
+    ];
+    let key = cache_key(model, 64, &tokens).unwrap();
+
+    let mut seqs = gen.sequences(2, 64).unwrap();
+    set_tokens_cached(&mut seqs, &tokens, &path).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), key);
+
+    let mut seqs = gen.sequences(2, 64).unwrap();
+    assert!(seqs.load_state(&path, &key, &tokens).unwrap());
+    assert_eq!(seqs.tokens(0), tokens[0]);
+    assert_eq!(seqs.tokens(1), tokens[1]);
+
+    // Truncated and corrupted files are ignored and overwritten.
+    for file in [&key[..16], b"Not a prompt cache."] {
+        std::fs::write(&path, file).unwrap();
+
+        let mut seqs = gen.sequences(2, 64).unwrap();
+        assert!(!seqs.load_state(&path, &key, &tokens).unwrap());
+        set_tokens_cached(&mut seqs, &tokens, &path).unwrap();
+        assert_eq!(seqs.tokens(1), tokens[1]);
+        assert_eq!(std::fs::read(&path).unwrap(), key);
+    }
+
+    let other_model = MockModel::new(1);
+    assert_ne!(key, cache_key(other_model, 64, &tokens).unwrap());
+    assert_ne!(key, cache_key(model, 128, &tokens).unwrap());
+    assert_ne!(key, cache_key(model, 64, &tokens[..1]).unwrap());
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -1,3 +1,7 @@
+use std::collections::VecDeque;
+
+use log::trace;
+
 const N_BITS: usize = 32;
 const NORM: u64 = 1 << N_BITS;
 pub const MAX_RANGE_DENOMINATOR: u64 = HALF;
@@ -76,25 +80,60 @@ impl RangeEncoder {
 
         self.out_buf
     }
+
+    /// The number of bits output so far, not counting those that are only output when flushing.
+    pub fn bits_written(&self) -> usize {
+        self.out_buf.len()
+    }
 }
 
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
 pub struct RangeDecoder {
     low: u64,
     range: u64,
     in_buf: Vec<bool>,
     buf_pos: usize,
+    bits_decoded: f64,
+    /// The segments to decode after `in_buf`.
+    next_segments: VecDeque<Vec<bool>>,
+    /// The number of bits read from earlier segments.
+    bits_read_before: usize,
 }
 
 impl RangeDecoder {
     pub fn new(in_buf: Vec<bool>) -> Self {
+        Self::segmented(vec![in_buf])
+    }
+
+    /// Creates a decoder that decodes each segment as if by a separate decoder, one after the
+    /// other. Each segment starts at the first symbol after the previous one is done, so the
+    /// segments can be recovered independently by encoding the symbols from that point until
+    /// [`RangeEncoder::bits_written`] exceeds the segment's length plus one.
+    pub fn segmented(segments: Vec<Vec<bool>>) -> Self {
+        let mut next_segments = VecDeque::from(segments);
+
         Self {
             low: 0,
             range: 1,
-            in_buf,
+            in_buf: next_segments.pop_front().unwrap_or_default(),
             buf_pos: 0,
+            bits_decoded: 0.,
+            next_segments,
+            bits_read_before: 0,
         }
     }
 
+    fn segment_done(&self) -> bool {
+        // We are done once the padding 0 is encoded.
+        self.buf_pos > self.in_buf.len() + N_BITS + 1
+    }
+
     fn input_bit(&mut self) -> bool {
         // Pad the output with a single zero bit, then infinite ones.
         let out = self
@@ -124,16 +163,27 @@ impl RangeDecoder {
 
     pub fn decode_range(&mut self, low: u64, high: u64, denominator: u64) {
         self.fill_range();
-        eprintln!(
-            " {} {} {}",
-            -((high - low) as f64 / denominator as f64).log2(),
+        let bits = -((high - low) as f64 / denominator as f64).log2();
+        trace!(
+            "Decoded {bits} bits, read {} of {}",
             self.buf_pos,
             self.in_buf.len()
         );
+        self.bits_decoded += bits;
 
         let offset = self.range * low / denominator;
         self.low -= offset;
         self.range = self.range * high / denominator - offset;
+
+        if self.segment_done() {
+            if let Some(segment) = self.next_segments.pop_front() {
+                self.bits_read_before += self.buf_pos;
+                self.in_buf = segment;
+                self.buf_pos = 0;
+                self.low = 0;
+                self.range = 1;
+            }
+        }
     }
 
     pub fn decode(&mut self, table: &[u64], denominator: u64) -> usize {
@@ -148,8 +198,29 @@ impl RangeDecoder {
     }
 
     pub fn is_done(&self) -> bool {
-        // We are done once the padding 0 is encoded.
-        self.buf_pos > self.in_buf.len() + N_BITS + 1
+        self.segment_done() && self.next_segments.is_empty()
+    }
+
+    /// The number of bits that have been read so far, including ones that are only partially
+    /// decoded.
+    pub fn bits_read(&self) -> usize {
+        self.bits_read_before + self.buf_pos
+    }
+
+    /// The amount of information in all of the symbols decoded so far, in bits.
+    pub fn bits_decoded(&self) -> f64 {
+        self.bits_decoded
+    }
+
+    /// The number of bits left to read before decoding is done.
+    pub fn bits_remaining(&self) -> usize {
+        let later = self
+            .next_segments
+            .iter()
+            .map(|segment| segment.len() + N_BITS + 2)
+            .sum::<usize>();
+
+        (self.in_buf.len() + N_BITS + 2).saturating_sub(self.buf_pos) + later
     }
 }
 
@@ -170,6 +241,7 @@ pub fn bytes_to_bools(bytes: &[u8], len: Option<usize>) -> Vec<bool> {
         .collect()
 }
 
+#[cfg(test)]
 fn test_range_coding_case(table: &[u64], denom: u64, message: &[usize]) {
     let mut encoder = RangeEncoder::new();
 
@@ -214,3 +286,30 @@ fn test_range_coding() {
         &[3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3],
     );
 }
+
+#[test]
+fn test_segmented_range_coding() {
+    let table = [0, 5, 10, 15];
+    let segments = vec![
+        vec![true, false, true, true, false],
+        vec![false; 12],
+        vec![true; 7],
+    ];
+
+    let mut decoder = RangeDecoder::segmented(segments.clone());
+    let mut message = Vec::new();
+    while !decoder.is_done() {
+        message.push(decoder.decode(&table, 16));
+    }
+
+    // Each segment is recovered by encoding symbols until enough bits have been written.
+    let mut symbols = message.into_iter();
+    for segment in segments {
+        let mut encoder = RangeEncoder::new();
+        while encoder.bits_written() <= segment.len() + 1 {
+            encoder.encode(&table, 16, symbols.next().unwrap());
+        }
+        assert_eq!(&encoder.flush()[..segment.len()], segment.as_slice());
+    }
+    assert_eq!(symbols.next(), None);
+}
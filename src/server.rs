@@ -0,0 +1,499 @@
+//! An HTTP server that keeps a model loaded between requests.
+//!
+//! Every endpoint takes a JSON object in the body of a `POST` request, and responds with a JSON
+//! object:
+//!
+//! | Endpoint      | Request                                                  | Response                             |
+//! |---------------|----------------------------------------------------------|--------------------------------------|
+//! | `/encode`     | `message` or `bytes`, encoding settings                  | `text` and statistics                |
+//! | `/decode`     | `text`, decoding settings                                | `message` or `bytes`                 |
+//! | `/compress`   | `message`                                                | `compressed`                         |
+//! | `/decompress` | `compressed`                                             | `message`                            |
+//! | `/estimate`   | `message` or `bytes`, encoding settings, `sample_tokens` | [`Estimate`](crate::Estimate) fields |
+//!
+//! Settings have the same names as the command-line options, with underscores instead of dashes,
+//! and the same defaults. Hidden messages are encrypted when `passphrase` is given. Byte strings
+//! are arrays of numbers. Errors are reported as `{"error": ...}`.
+//!
+//! When `stream` is `true`, `/encode`, `/decode` and `/decompress` instead respond with
+//! newline-delimited JSON: a `{"piece": ...}` object for each piece of the text as it is generated,
+//! or of the message as it is decompressed, followed by the usual response. Hidden bytes are not
+//! streamed. The status of a streamed response is always 200, so a failure is reported by ending
+//! the stream with `{"error": ..., "status": ...}` instead, where `status` is the code that would
+//! have been sent.
+//!
+//! Bodies larger than [`MAX_BODY`] are rejected with 413, and settings that would generate more
+//! than [`MAX_TOKENS`] tokens with 400.
+
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    args::{DecodeArgs, EncodeArgs},
+    error::Error,
+    fec,
+    generation_context::LanguageModel,
+    stegano::Stegano,
+};
+
+/// The largest request body that is read, in bytes.
+pub const MAX_BODY: u64 = 16 << 20;
+/// The most tokens that a request can ask for, which bounds the memory that it can use.
+pub const MAX_TOKENS: usize = 1 << 16;
+
+/// An error caused by the request itself, rather than by processing it.
+#[derive(Debug)]
+struct BadRequest(u16, String);
+
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.1)
+    }
+}
+
+impl std::error::Error for BadRequest {}
+
+/// Rejects a setting that is larger than `max`.
+fn check_max<T: PartialOrd + std::fmt::Display>(name: &str, value: T, max: T) -> Result<()> {
+    if value > max {
+        return Err(BadRequest(400, format!("`{name}` must be at most {max}")).into());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct MessageRequest {
+    message: Option<String>,
+    bytes: Option<Vec<u8>>,
+}
+
+enum Message {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl MessageRequest {
+    fn into_message(self) -> Result<Message> {
+        match (self.message, self.bytes) {
+            (Some(message), None) => Ok(Message::Text(message)),
+            (None, Some(bytes)) => Ok(Message::Bytes(bytes)),
+            _ => {
+                Err(BadRequest(400, "expected exactly one of `message` and `bytes`".into()).into())
+            }
+        }
+    }
+}
+
+fn default_sample_tokens() -> usize {
+    256
+}
+
+#[derive(Deserialize)]
+struct EncodeRequest {
+    #[serde(flatten)]
+    message: MessageRequest,
+    #[serde(flatten)]
+    args: EncodeArgs,
+    passphrase: Option<String>,
+    #[serde(default = "default_sample_tokens")]
+    sample_tokens: usize,
+}
+
+impl EncodeRequest {
+    fn parse(body: Value) -> Result<(Message, EncodeArgs, usize)> {
+        let mut request: Self = serde_json::from_value(body)?;
+        request.args.key.passphrase = request.passphrase;
+
+        check_max("fec", request.args.fec, fec::MAX_PARITY)?;
+        check_max("skip_start", request.args.skip_start, MAX_TOKENS)?;
+        check_max("token_count", request.args.token_count, MAX_TOKENS)?;
+        check_max("sample_tokens", request.sample_tokens, MAX_TOKENS)?;
+        Ok((
+            request.message.into_message()?,
+            request.args,
+            request.sample_tokens,
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct DecodeRequest {
+    text: String,
+    #[serde(flatten)]
+    args: DecodeArgs,
+    passphrase: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompressRequest {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct DecompressRequest {
+    compressed: Vec<u8>,
+}
+
+/// Writes the body of a response with chunked transfer encoding, sending each write as a chunk.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Writes the head of a successful response, leaving the body open.
+    fn new(mut inner: W, content_type: &str) -> Result<Self> {
+        write!(
+            inner,
+            "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\nTransfer-Encoding: chunked\r\n\
+             Connection: close\r\n\r\n"
+        )?;
+        Ok(Self { inner })
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        Ok(self.inner.flush()?)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            self.inner.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes each piece of text written to it as a line containing a `{"piece": ...}` object.
+struct PieceWriter<'w, W: Write>(&'w mut W);
+
+impl<W: Write> Write for PieceWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !buf.is_empty() {
+            let line = json!({ "piece": String::from_utf8_lossy(buf) });
+            self.0.write_all(format!("{line}\n").as_bytes())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn encode<M: LanguageModel>(
+    stegano: &mut Stegano<M>,
+    body: Value,
+    out: &mut impl Write,
+) -> Result<Value> {
+    let (message, args, _) = EncodeRequest::parse(body)?;
+
+    let report = match message {
+        Message::Text(message) => stegano.encode_report(&message, &args, out)?,
+        Message::Bytes(bytes) => stegano.encode_bytes_report(&bytes, &args, out)?,
+    };
+    Ok(report.to_json())
+}
+
+fn estimate<M: LanguageModel>(stegano: &mut Stegano<M>, body: Value) -> Result<Value> {
+    let (message, args, sample_tokens) = EncodeRequest::parse(body)?;
+
+    let estimate = match message {
+        Message::Text(message) => stegano.estimate(&message, &args, sample_tokens)?,
+        Message::Bytes(bytes) => stegano.estimate_bytes(&bytes, &args, sample_tokens)?,
+    };
+    Ok(estimate.to_json())
+}
+
+/// The endpoints that can stream their responses.
+const STREAMED_ENDPOINTS: [&str; 3] = ["/encode", "/decode", "/decompress"];
+
+/// Handles a request, writing the pieces of text that [`STREAMED_ENDPOINTS`] produce to `out`.
+fn route<M: LanguageModel>(
+    stegano: &mut Stegano<M>,
+    url: &str,
+    body: Value,
+    out: &mut impl Write,
+) -> Result<Value> {
+    match url {
+        "/encode" => encode(stegano, body, out),
+        "/decode" => {
+            let DecodeRequest {
+                text,
+                mut args,
+                passphrase,
+            } = serde_json::from_value(body)?;
+            args.key.passphrase = passphrase;
+            check_max("fec", args.fec, fec::MAX_PARITY)?;
+            check_max("skip_start", args.skip_start, MAX_TOKENS)?;
+
+            Ok(stegano.decode_payload_to(&text, &args, out)?.to_json())
+        }
+        "/compress" => {
+            let request: CompressRequest = serde_json::from_value(body)?;
+            Ok(json!({ "compressed": stegano.compress(&request.message)? }))
+        }
+        "/decompress" => {
+            let request: DecompressRequest = serde_json::from_value(body)?;
+            Ok(json!({ "message": stegano.decompress_to(&request.compressed, out)? }))
+        }
+        "/estimate" => estimate(stegano, body),
+        _ => Err(BadRequest(404, format!("unknown endpoint {url}")).into()),
+    }
+}
+
+/// Returns the HTTP status code for an error.
+fn status(e: &anyhow::Error) -> u16 {
+    if let Some(BadRequest(status, _)) = e.downcast_ref() {
+        *status
+    } else if e.is::<serde_json::Error>() {
+        400
+    } else {
+        match e.downcast_ref() {
+            Some(Error::Other(_)) | None => 500,
+            Some(_) => 422,
+        }
+    }
+}
+
+fn error_json(e: &anyhow::Error) -> Value {
+    json!({ "error": format!("{e:#}") })
+}
+
+/// Reads the body of `request` as JSON, unless it is larger than [`MAX_BODY`].
+fn read_body(request: &mut Request) -> Result<Value> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)?;
+    if body.len() as u64 > MAX_BODY {
+        let reason = format!("the body is larger than {MAX_BODY} bytes");
+        return Err(BadRequest(413, reason).into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn respond(request: Request, status: u16, body: Value) -> Result<()> {
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+
+    // Large bodies would otherwise be sent chunked. Clients reading a single JSON object expect a
+    // Content-Length instead.
+    Ok(request.respond(
+        Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(content_type)
+            .with_chunked_threshold(usize::MAX),
+    )?)
+}
+
+/// Responds to a single request.
+pub fn handle_request<M: LanguageModel>(
+    stegano: &mut Stegano<M>,
+    mut request: Request,
+) -> Result<()> {
+    if *request.method() != Method::Post {
+        let e: anyhow::Error = BadRequest(405, "only POST requests are supported".into()).into();
+        return respond(request, status(&e), error_json(&e));
+    }
+
+    let body = match read_body(&mut request) {
+        Ok(body) => body,
+        Err(e) => return respond(request, status(&e), error_json(&e)),
+    };
+
+    let url = request.url().to_string();
+    if STREAMED_ENDPOINTS.contains(&url.as_str()) && body["stream"] == true {
+        let mut out = ChunkedWriter::new(request.into_writer(), "application/x-ndjson")?;
+        let last = match route(stegano, &url, body, &mut PieceWriter(&mut out)) {
+            Ok(response) => response,
+            Err(e) => {
+                let mut error = error_json(&e);
+                error["status"] = status(&e).into();
+                error
+            }
+        };
+        out.write_all(format!("{last}\n").as_bytes())?;
+        return out.finish();
+    }
+
+    match route(stegano, &url, body, &mut std::io::sink()) {
+        Ok(response) => respond(request, 200, response),
+        Err(e) => respond(request, status(&e), error_json(&e)),
+    }
+}
+
+/// Serves requests at `address` until the process is stopped. Requests are handled one at a time,
+/// since they all use the same model context.
+pub fn serve<M: LanguageModel>(stegano: &mut Stegano<M>, address: &str) -> Result<()> {
+    let server = Server::http(address).map_err(|e| anyhow!("Could not start server: {e}"))?;
+    info!("Listening on http://{}", server.server_addr());
+
+    for request in server.incoming_requests() {
+        if let Err(e) = handle_request(stegano, request) {
+            warn!("Could not respond to request: {e:#}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn post(address: std::net::SocketAddr, path: &str, body: Value) -> (u16, String) {
+    use std::io::Read;
+
+    let body = body.to_string();
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+#[test]
+fn test_server() {
+    use crate::mock::{MockContext, MockModel};
+
+    let model = MockModel::new(0);
+    let mut stegano = Stegano::from_language_model(MockContext::new(&model));
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let address = server.server_addr().to_ip().unwrap();
+
+    std::thread::scope(|s| {
+        let client = s.spawn(|| {
+            let (status, body) = post(address, "/compress", json!({ "message": "Hello" }));
+            assert_eq!(status, 200);
+            let compressed = serde_json::from_str::<Value>(&body).unwrap()["compressed"].clone();
+
+            let (status, body) = post(
+                address,
+                "/decompress",
+                json!({ "compressed": compressed.clone() }),
+            );
+            assert_eq!(status, 200);
+            assert_eq!(
+                serde_json::from_str::<Value>(&body).unwrap(),
+                json!({ "message": "Hello" })
+            );
+
+            let (status, body) = post(
+                address,
+                "/decompress",
+                json!({ "compressed": compressed, "stream": true }),
+            );
+            assert_eq!(status, 200);
+            assert!(body.contains(r#"{"piece":"#));
+            assert!(body.contains(r#"{"message":"Hello"}"#));
+
+            let settings = json!({
+                "message": "This is a secret.",
+                "prompt": "Write a paragraph about range coding.",
+                "token_count": 512,
+                "passphrase": "hunter2",
+            });
+            let (status, body) = post(address, "/encode", settings);
+            assert_eq!(status, 200);
+            let text = serde_json::from_str::<Value>(&body).unwrap()["text"].clone();
+
+            let (status, body) = post(
+                address,
+                "/decode",
+                json!({ "text": text, "passphrase": "hunter2" }),
+            );
+            assert_eq!(status, 200);
+            assert_eq!(
+                serde_json::from_str::<Value>(&body).unwrap(),
+                json!({ "message": "This is a secret." })
+            );
+
+            let (status, body) = post(address, "/decode", json!({ "text": text }));
+            assert_eq!(status, 422);
+            assert!(body.contains("error"));
+
+            let (status, body) = post(
+                address,
+                "/encode",
+                json!({
+                    "bytes": [0, 255],
+                    "prompt": "Write a paragraph about range coding.",
+                    "token_count": 512,
+                    "stream": true,
+                }),
+            );
+            assert_eq!(status, 200);
+            assert!(body.contains(r#"{"piece":"#));
+            assert!(body.contains(r#""text":"#));
+
+            let (status, _) = post(address, "/nonexistent", json!({}));
+            assert_eq!(status, 404);
+
+            let (status, _) = post(address, "/encode", json!({ "prompt": "No message." }));
+            assert_eq!(status, 400);
+
+            let (status, body) = post(
+                address,
+                "/encode",
+                json!({ "message": "Hello", "token_count": MAX_TOKENS + 1 }),
+            );
+            assert_eq!(status, 400);
+            assert!(body.contains("token_count"));
+
+            let (status, _) = post(
+                address,
+                "/estimate",
+                json!({ "message": "Hello", "sample_tokens": usize::MAX }),
+            );
+            assert_eq!(status, 400);
+
+            let (status, _) = post(address, "/decode", json!({ "text": "Hello", "fec": 255 }));
+            assert_eq!(status, 400);
+
+            let padding = " ".repeat(MAX_BODY as usize);
+            let (status, _) = post(address, "/decompress", json!({ "padding": padding }));
+            assert_eq!(status, 413);
+
+            // The message can't fit in 4 tokens, which is only found after the stream has started.
+            let (status, body) = post(
+                address,
+                "/encode",
+                json!({ "message": "This is a secret.", "token_count": 4, "stream": true }),
+            );
+            assert_eq!(status, 200);
+            let last = body
+                .lines()
+                .rev()
+                .find(|line| line.starts_with('{'))
+                .unwrap();
+            let last = serde_json::from_str::<Value>(last).unwrap();
+            assert!(last["error"].is_string());
+            assert_eq!(last["status"], 422);
+        });
+
+        for _ in 0..14 {
+            handle_request(&mut stegano, server.recv().unwrap()).unwrap();
+        }
+        client.join().unwrap();
+    });
+}
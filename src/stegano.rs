@@ -0,0 +1,205 @@
+use std::io::Write;
+
+use llama_cpp_2::context::params::LlamaContextParams;
+
+use crate::{
+    args::{DecodeArgs, EncodeArgs},
+    container::Payload,
+    error::{Error, Result},
+    estimate::Estimate,
+    generation_context::{default_context_params, GenerationContext, LanguageModel, Model},
+    range_coder::{bools_to_bytes, bytes_to_bools},
+    steganography::{bytes_payload, EncodeReport, Steganography, TokenStats},
+    trace::Divergence,
+};
+
+/// Hides messages in text generated by a language model, and recovers them from that text.
+///
+/// The model file and all settings must be exactly the same when decoding as they were when
+/// encoding, or the recovered message will be corrupted.
+pub struct Stegano<M> {
+    gen: M,
+}
+
+impl<'a> Stegano<GenerationContext<'a>> {
+    /// Creates a new [`Stegano`] using `model` with the default context parameters.
+    pub fn new(model: &'a Model) -> Result<Self> {
+        Self::with_params(model, default_context_params())
+    }
+
+    /// Creates a new [`Stegano`] using `model` with the given context parameters.
+    pub fn with_params(model: &'a Model, params: LlamaContextParams) -> Result<Self> {
+        Ok(Self::from_language_model(GenerationContext::new(
+            model, params,
+        )?))
+    }
+}
+
+impl<M: LanguageModel> Stegano<M> {
+    /// Creates a new [`Stegano`] backed by any [`LanguageModel`].
+    pub fn from_language_model(gen: M) -> Self {
+        Self { gen }
+    }
+
+    /// Compresses `message` with the model, then hides it in text generated from `args.prompt`.
+    pub fn encode(&mut self, message: &str, args: &EncodeArgs) -> Result<String> {
+        Ok(self.gen.encode_compressed(message, args)?)
+    }
+
+    /// Hides `message` in text generated from `args.prompt` without compressing it.
+    pub fn encode_bytes(&mut self, message: &[u8], args: &EncodeArgs) -> Result<String> {
+        Ok(self.gen.encode_message(message.to_vec(), args)?)
+    }
+
+    /// Like [`Stegano::encode`], but also writes each piece of the text to `out` as soon as it is
+    /// generated.
+    pub fn encode_to(
+        &mut self,
+        message: &str,
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<String> {
+        Ok(self.encode_report(message, args, out)?.text)
+    }
+
+    /// Like [`Stegano::encode_bytes`], but also writes each piece of the text to `out` as soon as
+    /// it is generated.
+    pub fn encode_bytes_to(
+        &mut self,
+        message: &[u8],
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<String> {
+        Ok(self.encode_bytes_report(message, args, out)?.text)
+    }
+
+    /// Like [`Stegano::encode_to`], but also returns statistics about how the message was hidden.
+    pub fn encode_report(
+        &mut self,
+        message: &str,
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<EncodeReport> {
+        let bools = self.gen.compressed_payload(message)?;
+        let mut report = self.gen.encode_bools_report(bools, args, out)?;
+        report.message_bits = message.len() * 8;
+        Ok(report)
+    }
+
+    /// Like [`Stegano::encode_bytes_to`], but also returns statistics about how the message was
+    /// hidden.
+    pub fn encode_bytes_report(
+        &mut self,
+        message: &[u8],
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<EncodeReport> {
+        let bools = bytes_payload(message.to_vec());
+        let mut report = self.gen.encode_bools_report(bools, args, out)?;
+        report.message_bits = message.len() * 8;
+        Ok(report)
+    }
+
+    /// Recovers a message hidden with [`Stegano::encode`].
+    pub fn decode(&mut self, text: &str, args: &DecodeArgs) -> Result<String> {
+        Ok(self.gen.decode_compressed(text, args)?)
+    }
+
+    /// Like [`Stegano::decode`], but also writes each piece of the message to `out` as soon as it
+    /// is decompressed. The message is only checked once all of it has been written.
+    pub fn decode_to(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        out: &mut impl Write,
+    ) -> Result<String> {
+        match self.decode_payload_to(text, args, out)? {
+            Payload::Text(text) => Ok(text),
+            Payload::Bytes(_) => Err(Error::InvalidMessage(
+                "expected compressed text, but the message contains raw bytes".into(),
+            )),
+        }
+    }
+
+    /// Recovers a message hidden with either [`Stegano::encode`] or [`Stegano::encode_bytes`] as
+    /// bytes.
+    pub fn decode_bytes(&mut self, text: &str, args: &DecodeArgs) -> Result<Vec<u8>> {
+        Ok(self.gen.decode_messsage(text, args)?)
+    }
+
+    /// Recovers a message hidden with either [`Stegano::encode`] or [`Stegano::encode_bytes`],
+    /// along with the kind of message it was.
+    pub fn decode_payload(&mut self, text: &str, args: &DecodeArgs) -> Result<Payload> {
+        self.decode_payload_to(text, args, &mut std::io::sink())
+    }
+
+    /// Like [`Stegano::decode_payload`], but if the message is compressed text, also writes each
+    /// piece of it to `out` as soon as it is decompressed. Bytes are not written.
+    pub fn decode_payload_to(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        out: &mut impl Write,
+    ) -> Result<Payload> {
+        Ok(self.gen.decode_payload(text, args, out)?)
+    }
+
+    /// Estimates the number of tokens that [`Stegano::encode`] needs to hide `message`, by
+    /// generating `sample_tokens` tokens from `args.prompt`.
+    pub fn estimate(
+        &mut self,
+        message: &str,
+        args: &EncodeArgs,
+        sample_tokens: usize,
+    ) -> Result<Estimate> {
+        Ok(self.gen.estimate_compressed(message, args, sample_tokens)?)
+    }
+
+    /// Estimates the number of tokens that [`Stegano::encode_bytes`] needs to hide `message`, by
+    /// generating `sample_tokens` tokens from `args.prompt`.
+    pub fn estimate_bytes(
+        &mut self,
+        message: &[u8],
+        args: &EncodeArgs,
+        sample_tokens: usize,
+    ) -> Result<Estimate> {
+        Ok(self
+            .gen
+            .estimate_message(message.to_vec(), args, sample_tokens)?)
+    }
+
+    /// Compresses `message` using the model's predictions.
+    pub fn compress(&mut self, message: &str) -> Result<Vec<u8>> {
+        Ok(bools_to_bytes(&self.gen.compress_message(message)?))
+    }
+
+    /// Decompresses a message compressed with [`Stegano::compress`].
+    pub fn decompress(&mut self, compressed: &[u8]) -> Result<String> {
+        self.decompress_to(compressed, &mut std::io::sink())
+    }
+
+    /// Like [`Stegano::decompress`], but also writes each piece of the message to `out` as soon as
+    /// it is decompressed.
+    pub fn decompress_to(&mut self, compressed: &[u8], out: &mut impl Write) -> Result<String> {
+        Ok(self
+            .gen
+            .decompress_message(bytes_to_bools(compressed, None), out)?)
+    }
+
+    /// Decodes `text` and returns the first token where the decoder's decisions differ from those
+    /// recorded in `encoder_trace`, to tell whether a failure to decode is caused by changes to
+    /// the text, different settings, or numerical differences between machines.
+    pub fn diff_decode(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        encoder_trace: &[TokenStats],
+    ) -> Result<Option<Divergence>> {
+        Ok(self.gen.diff_decode(text, args, encoder_trace)?)
+    }
+
+    /// Returns the underlying language model, for lower-level access.
+    pub fn language_model(&mut self) -> &mut M {
+        &mut self.gen
+    }
+}
@@ -1,14 +1,27 @@
-use anyhow::{bail, Context, Result};
+use std::{collections::VecDeque, io::Write};
+
+use anyhow::{ensure, Result};
 use llama_cpp_2::{
-    model::{AddBos, LlamaChatMessage},
     sampling::LlamaSampler,
     token::{data::LlamaTokenData, data_array::LlamaTokenDataArray, LlamaToken},
 };
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    generation_context::{generate_text, GenerationContext, LanguageModel},
-    range_coder::{RangeDecoder, RangeEncoder, MAX_RANGE_DENOMINATOR},
-    DecodeArgs, EncodeArgs,
+    args::{DecodeArgs, EncodeArgs, Metric},
+    container::{pack, unpack, Payload, PayloadKind, TEXT_CRC_BITS},
+    crypto::{self, decrypt_bools, encrypt_bools},
+    error::Error,
+    estimate::Estimate,
+    fec,
+    generation_context::{generate_text, LanguageModel, Sequences, Vocabulary},
+    normalize::{is_stable, normalize, retokenization_mismatch},
+    prompt_cache::set_tokens_cached,
+    range_coder::{
+        bools_to_bytes, bytes_to_bools, RangeDecoder, RangeEncoder, MAX_RANGE_DENOMINATOR,
+    },
+    trace::{first_divergence, write_trace, Divergence},
 };
 
 fn softmax(array: &mut LlamaTokenDataArray) {
@@ -43,7 +56,11 @@ fn softmax_no_sort(array: &mut LlamaTokenDataArray) {
     }
 }
 
-fn distinguishability(ps: &mut LlamaTokenDataArray, qs: &mut LlamaTokenDataArray) -> f64 {
+fn distinguishability(
+    ps: &mut LlamaTokenDataArray,
+    qs: &mut LlamaTokenDataArray,
+    metric: Metric,
+) -> f64 {
     if !ps.sorted {
         softmax(ps);
     }
@@ -57,46 +74,70 @@ fn distinguishability(ps: &mut LlamaTokenDataArray, qs: &mut LlamaTokenDataArray
         table[q.id().0 as usize] = q.p();
     }
 
-    ps.data
+    let pairs = ps
+        .data
         .iter()
-        .map(|p| p.p().max(table[p.id().0 as usize]) as f64)
-        .sum::<f64>()
-        * 0.5
+        .map(|p| (p.p() as f64, table[p.id().0 as usize] as f64));
+
+    match metric {
+        Metric::Distinguishability => pairs.map(|(p, q)| p.max(q)).sum::<f64>() * 0.5,
+        Metric::TotalVariation => pairs.map(|(p, q)| (p - q).abs()).sum::<f64>() * 0.5,
+        Metric::JensenShannon => {
+            let kl = |p: f64, m: f64| if p > 0. { p * (p / m).log2() } else { 0. };
+
+            pairs
+                .map(|(p, q)| {
+                    let m = (p + q) * 0.5;
+                    (kl(p, m) + kl(q, m)) * 0.5
+                })
+                .sum::<f64>()
+        }
+    }
 }
 
-fn coding_windows<'a>(
-    array: &'a mut LlamaTokenDataArray,
+/// Filters `data_array` down to the tokens that may carry part of the message when following the
+/// tokens of the text so far, `context`, and computes their probabilities under the sampler
+/// settings. Returns `false` if no tokens remain.
+fn coding_candidates(
+    data_array: &mut LlamaTokenDataArray,
+    model: impl Vocabulary,
+    context: &[LlamaToken],
     args: &DecodeArgs,
-) -> impl Iterator<Item = &'a [LlamaTokenData]> {
-    softmax(array);
-
-    let mut array2 = array.clone();
-
-    array2.apply_sampler(&LlamaSampler::chain_simple([
+) -> bool {
+    data_array.apply_sampler(&LlamaSampler::chain_simple([
         LlamaSampler::min_p(args.min_p, 1),
         LlamaSampler::top_k(args.top_k as i32),
         LlamaSampler::temp(args.temp),
     ]));
-    softmax(&mut array2);
 
-    array.data[0..array2.data.len()].clone_from_slice(array2.data.as_slice());
-
-    let last_id = array
+    // Ending the text would cut the message short, so end of generation tokens never carry it.
+    data_array.data.retain(|d| !model.is_eog_token(d.id()));
+    // The decoder could not recover tokens that it would tokenize differently.
+    data_array
         .data
-        .get(array2.data.len())
-        .map(|x| x.id())
-        .unwrap_or(LlamaToken(-1));
-    let mut first_chunk = true;
+        .retain(|d| is_stable(&model, context, d.id()));
+    if data_array.data.is_empty() {
+        return false;
+    }
 
-    array.data.chunk_by(move |_, d| {
-        if d.id() == last_id {
-            first_chunk = false;
-        }
-        first_chunk
-    })
+    softmax(data_array);
+    // In robust mode, nearly tied candidates could swap places on another machine, which would
+    // swap their intervals too, so the candidates are ordered by their ids instead.
+    if args.robust {
+        data_array.data.sort_by_key(|d| d.id().0);
+    }
+    true
 }
 
 fn to_prob_table(data: &[LlamaTokenData]) -> (Vec<u64>, u64) {
+    // Rounding up to an interval of 1 adds at most 1 per token, which must not take the
+    // denominator over the limit.
+    to_scaled_prob_table(data, (MAX_RANGE_DENOMINATOR - data.len() as u64) as f64)
+}
+
+/// Converts probabilities to a table of intervals that add up to about `scale`. Every token is
+/// given an interval of at least 1.
+fn to_scaled_prob_table(data: &[LlamaTokenData], scale: f64) -> (Vec<u64>, u64) {
     let total_prob = data.iter().map(|d| d.p() as f64).sum::<f64>();
 
     let mut out = Vec::new();
@@ -104,149 +145,618 @@ fn to_prob_table(data: &[LlamaTokenData]) -> (Vec<u64>, u64) {
 
     for d in data {
         out.push(sum);
-        sum += ((d.p() as f64 / total_prob * MAX_RANGE_DENOMINATOR as f64) as u64).max(1);
+        sum += ((d.p() as f64 / total_prob * scale) as u64).max(1);
     }
 
     (out, sum.max(1))
 }
 
-// pub fn recover_message(
-// token_data: Vec<LlamaTokenDataArray>,
-// tokens: &[LlamaToken],
-// args: &DecodeArgs,
-// ) -> Vec<bool> {
-// let mut encoder = RangeEncoder::new();
-
-// for (mut data_array, &token) in token_data.into_iter().zip(tokens).skip(args.skip_start) {
-// let window = coding_windows(&mut data_array, args)
-// .find(|window| window.iter().any(|d| d.id() == token))
-// .expect("No window contains the token");
-// let token_i = window
-// .iter()
-// .position(|d| d.id() == token)
-// .expect("The window does not contain the token!");
+/// The number of bits that probabilities are quantized to in robust mode.
+const ROBUST_PROBABILITY_BITS: u32 = 6;
 
-// let (table, denominator) = to_prob_table(window);
-// encoder.encode(&table, denominator, token_i);
-// }
-
-// encoder.flush()
-// }
+/// Converts the coding candidates' probabilities to a table of intervals, quantizing them to a
+/// coarse grid in robust mode so that tiny differences in the probabilities rarely change it.
+fn coding_prob_table(data: &[LlamaTokenData], args: &DecodeArgs) -> (Vec<u64>, u64) {
+    if args.robust {
+        to_scaled_prob_table(data, (1u64 << ROBUST_PROBABILITY_BITS) as f64)
+    } else {
+        to_prob_table(data)
+    }
+}
 
-// pub fn sample_steganography(
-// normal: &mut GenerationContext,
-// steganographer: &mut GenerationContext,
-// decoder: &mut RangeDecoder,
-// args: &EncodeArgs,
-// ) -> Result<LlamaToken> {
-// if steganographer.tokens().len() <= args.skip_start {
-// let mut tokens = normal.get_token_data();
-// softmax(&mut tokens);
+/// Returns whether a change in the steganographer's predictions smaller than the robust margin
+/// could change whether a token hides part of the message, or which tokens may carry it.
+/// `data_array` must be sorted and normalized, as [`distinguishability`] leaves it.
+fn near_cutoff(data_array: &LlamaTokenDataArray, metric: f64, args: &DecodeArgs) -> bool {
+    let margin = args.robust_margin;
 
-// let token = tokens.data[0].id();
-// normal.add_token(token)?;
-// steganographer.add_token(token)?;
+    if (metric - args.threshold).abs() < margin {
+        return true;
+    }
+    if metric > args.threshold {
+        return false;
+    }
 
-// return Ok(token);
-// }
+    // MinP keeps the tokens whose logits are at least `ln(min_p)` above the largest.
+    let logits = &data_array.data;
+    let min_p_cutoff = logits[0].logit() as f64 + (args.min_p as f64).ln();
+    if args.min_p > 0.
+        && logits
+            .iter()
+            .any(|d| (d.logit() as f64 - min_p_cutoff).abs() < margin)
+    {
+        return true;
+    }
 
-// if decoder.is_done() {
-// let mut tokens = normal.get_token_data();
-// softmax(&mut tokens);
+    args.top_k > 0
+        && logits
+            .get(args.top_k)
+            .is_some_and(|d| ((logits[args.top_k - 1].logit() - d.logit()) as f64) < margin)
+}
 
-// let token = tokens.data[0].id();
-// normal.add_token(token)?;
+/// Returns whether a change in the coding candidates' log-probabilities smaller than the robust
+/// margin could change their quantized table.
+fn near_quantization_step(data: &[LlamaTokenData], args: &DecodeArgs) -> bool {
+    let scale = (1u64 << ROBUST_PROBABILITY_BITS) as f64;
+    let total_prob = data.iter().map(|d| d.p() as f64).sum::<f64>();
 
-// return Ok(token);
-// }
+    // Every token gets an interval of at least 1, so the step from 0 to 1 makes no difference.
+    data.iter().any(|d| {
+        let x = d.p() as f64 / total_prob * scale;
+        x > 1.5 && (x - x.round()).abs() < x * args.robust_margin
+    })
+}
 
-// let logits = normal.get_token_data();
-// let mut data_array = steganographer.get_token_data();
+/// Returns the full prompt text given to the auxiliary model.
+fn aux_prompt(model: impl Vocabulary, args: &DecodeArgs) -> Result<String> {
+    if args.raw_aux_prompt {
+        Ok(args.aux_prompt.clone())
+    } else {
+        model.apply_chat_template(&args.aux_prompt)
+    }
+}
 
-// let window = coding_windows(&mut data_array, &args.as_decode_args())
-// .max_by_key(|window| {
-// let (table, denominator) = to_prob_table(window);
-// let token_i = decoder.selected_symbol(&table, denominator);
-// let token = window[token_i].id();
+/// The sequence that has access to the user's prompt, and chooses tokens that do not carry the
+/// message.
+const WRITER: usize = 0;
+/// The sequence without a prompt, which chooses tokens that carry the message.
+const STEGANOGRAPHER: usize = 1;
+/// The sequence with the auxiliary prompt.
+const AUXILIARY: usize = 2;
+const N_SEQUENCES: usize = 3;
+
+/// The part of the range coder's range that a token was assigned, as fractions of `denominator`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interval {
+    pub low: u64,
+    pub high: u64,
+    pub denominator: u64,
+}
 
-// OrderedFloat(logits.data[token.0 as usize].logit())
-// })
-// .context("No windows")?;
+impl Interval {
+    fn new(table: &[u64], denominator: u64, i: usize) -> Self {
+        Self {
+            low: table[i],
+            high: table.get(i + 1).copied().unwrap_or(denominator),
+            denominator,
+        }
+    }
 
-// let (table, denominator) = to_prob_table(window);
-// let token_i = decoder.decode(&table, denominator);
-// let token = window[token_i].id();
+    /// The amount of information that choosing this interval carries, in bits.
+    pub fn bits(&self) -> f64 {
+        -((self.high - self.low) as f64 / self.denominator as f64).log2()
+    }
+}
 
-// normal.add_token(token)?;
-// steganographer.add_token(token)?;
+/// What happened when choosing a single token of the cover text.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenStats {
+    /// The id of the chosen token.
+    pub token: i32,
+    /// The value of the metric comparing the steganographer's and auxiliary model's predictions.
+    pub metric: f64,
+    /// Whether the token was chosen by the steganographer to hide part of the message.
+    pub embedded: bool,
+    /// The number of bits of the message that the token hides.
+    pub bits: f64,
+    /// The number of tokens that could have hidden part of the message, if they were considered.
+    pub candidates: Option<usize>,
+    /// The index of the token among the candidates, if it is one of them.
+    pub index: Option<usize>,
+    /// The interval of the token, if it hides part of the message.
+    pub interval: Option<Interval>,
+    /// Whether, in robust mode, the decisions made for the token were within the robust margin of
+    /// changing.
+    #[serde(default)]
+    pub fragile: bool,
+}
 
-// Ok(token)
-// }
+impl TokenStats {
+    pub fn token(&self) -> LlamaToken {
+        LlamaToken(self.token)
+    }
 
-const AUX_PROMPT: &str = "<|start_header_id|>user<|end_header_id|>\n\nWrite only about yoga. You are absolutely obsessed with yoga. If you find yourself writing about something other than yoga, quickly change the topic back to yoga. Yoga is love, yoga is life.<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n";
-const THRESHOLD: f64 = 0.60;
+    /// Returns the statistics of a token that does not hide part of the message.
+    fn unembedded(token: LlamaToken, metric: f64, candidates: Option<usize>) -> Self {
+        Self {
+            token: token.0,
+            metric,
+            embedded: false,
+            bits: 0.,
+            candidates,
+            index: None,
+            interval: None,
+            fragile: false,
+        }
+    }
+}
 
+/// Chooses the next token and adds it to every sequence.
+///
+/// If `detour` is nonzero and the writer chooses the token, it chooses its `detour`-th most likely
+/// token instead of the most likely one, and `detour` is reset to zero.
+/// The writer never chooses a token in `excluded`.
 pub fn sample_steganography(
-    steganographer: &mut GenerationContext,
-    auxilliary: &mut GenerationContext,
-    normal: &mut GenerationContext,
+    seqs: &mut impl Sequences,
     decoder: &mut RangeDecoder,
-    _args: &EncodeArgs,
-) -> Result<LlamaToken> {
-    let mut steg_data = steganographer.get_token_data();
-    let mut aux_data = auxilliary.get_token_data();
+    args: &DecodeArgs,
+    detour: &mut usize,
+    excluded: &[LlamaToken],
+) -> Result<TokenStats> {
+    let model = seqs.model();
+    let mut steg_data = seqs.get_token_data(STEGANOGRAPHER);
+    let mut aux_data = seqs.get_token_data(AUXILIARY);
 
-    let distinguishability = distinguishability(&mut steg_data, &mut aux_data);
+    let distinguishability = distinguishability(&mut steg_data, &mut aux_data, args.metric);
 
-    eprintln!(" {distinguishability} ");
+    // The first tokens are always chosen by the writer, so that it can establish the topic of the
+    // text before the steganographer starts choosing tokens.
+    let after_prefix = seqs.tokens(STEGANOGRAPHER).len() > args.skip_start;
+    let text = text_tokens(&*seqs);
 
-    let token = if distinguishability > THRESHOLD {
-        normal.get_token_data().sample_token_greedy()
-    } else {
-        steg_data.apply_sampler(&LlamaSampler::min_p(0.01, 1));
-        let (table, denominator) = to_prob_table(&steg_data.data);
+    let mut fragile =
+        args.robust && after_prefix && near_cutoff(&steg_data, distinguishability, args);
+
+    let candidates = (after_prefix && distinguishability <= args.threshold).then(|| {
+        coding_candidates(&mut steg_data, model, text, args);
+        steg_data.data.len()
+    });
+    let embedded = candidates.is_some_and(|n| n > 0);
+
+    let mut stats = if embedded {
+        fragile |= args.robust && near_quantization_step(&steg_data.data, args);
+
+        let (table, denominator) = coding_prob_table(&steg_data.data, args);
         let token_i = decoder.decode(&table, denominator);
-        steg_data.data[token_i].id()
+        let interval = Interval::new(&table, denominator, token_i);
+
+        TokenStats {
+            token: steg_data.data[token_i].id().0,
+            metric: distinguishability,
+            embedded,
+            bits: interval.bits(),
+            candidates,
+            index: Some(token_i),
+            interval: Some(interval),
+            fragile,
+        }
+    } else {
+        let mut writer_data = seqs.get_token_data(WRITER);
+        if !decoder.is_done() {
+            writer_data.data.retain(|d| !model.is_eog_token(d.id()));
+        }
+        writer_data
+            .data
+            .sort_by(|d1, d2| d2.logit().total_cmp(&d1.logit()));
+
+        // Detours may also change the prefix, since the first token after it can only be chosen
+        // differently by changing the text before it.
+        let rank = std::mem::take(detour);
+        // The writer also only chooses tokens that the decoder will tokenize the same way, unless
+        // there are none.
+        let token = writer_data
+            .data
+            .iter()
+            .map(|d| d.id())
+            .filter(|token| !excluded.contains(token))
+            .filter(|&token| model.is_eog_token(token) || is_stable(model, text, token))
+            .take(rank + 1)
+            .last()
+            .unwrap_or(writer_data.data[0].id());
+
+        TokenStats::unembedded(token, distinguishability, candidates)
     };
+    stats.fragile = fragile;
 
-    steganographer.add_token(token)?;
-    auxilliary.add_token(token)?;
-    normal.add_token(token)?;
+    seqs.add_token(stats.token())?;
+    trace!("{stats:?}");
 
-    Ok(token)
+    Ok(stats)
+}
+
+/// Returns the tokens of the text generated so far. The steganographer's prompt is empty, so these
+/// are its tokens after the BOS token.
+fn text_tokens(seqs: &impl Sequences) -> &[LlamaToken] {
+    let tokens = seqs.tokens(STEGANOGRAPHER);
+    tokens
+        .strip_prefix(&[seqs.model().token_bos()])
+        .unwrap_or(tokens)
+}
+
+/// The number of tokens between the points that [`Encoder`] can backtrack to.
+const BACKTRACK_WINDOW: usize = 32;
+/// The number of times [`Encoder`] generates a window again when the message is not being encoded
+/// quickly enough, when the text would be tokenized differently, or when it contains fragile
+/// tokens in robust mode.
+const MAX_RETRIES: usize = 4;
+
+/// Generates text that hides the bits read by a [`RangeDecoder`], as an iterator over its tokens.
+///
+/// Tokens are generated in windows. If, after a window, the rate that the message has been
+/// encoded at so far would not hide the rest of it in the remaining tokens, the window is thrown
+/// away and generated again, with the writer choosing a less likely token the first time that it
+/// chooses one. In robust mode, windows containing a [fragile](TokenStats::fragile) token are
+/// generated again in the same way, since a decoder on another machine could make a different
+/// decision for it. Fragile tokens after the end of the message are allowed, since the decoder
+/// ignores what they hide. Tokens are only yielded once their window is accepted.
+///
+/// Windows after which the text would no longer tokenize into the tokens generated so far are also
+/// generated again, with the writer not choosing the first token that tokenizes differently.
+/// Tokens chosen by the steganographer cannot be excluded without the decoder knowing, so these
+/// windows can only be fixed by the writer choosing differently before them.
+///
+/// If every attempt at a window is still behind after [`MAX_RETRIES`] retries, the one that hid
+/// the most of the message is generated again and kept, since later windows may catch up.
+/// Otherwise, if the last attempt still tokenizes differently, encoding fails with
+/// [`Error::RetokenizationMismatch`], and if it still contains fragile tokens, it fails with
+/// [`Error::FragileToken`].
+struct Encoder<'s, S> {
+    seqs: &'s mut S,
+    decoder: RangeDecoder,
+    args: &'s DecodeArgs,
+    token_count: usize,
+    n_generated: usize,
+    accepted: VecDeque<TokenStats>,
+    /// The statistics of every token of the text yielded so far.
+    stats: Vec<TokenStats>,
+    /// The tokens that the writer may not choose at each position of the text.
+    excluded: Vec<(usize, LlamaToken)>,
+    /// The number of tokens that the whole message was hidden in, once it has been.
+    message_end: Option<usize>,
+    finished: bool,
+}
+
+impl<'s, S: Sequences> Encoder<'s, S> {
+    fn new(
+        seqs: &'s mut S,
+        decoder: RangeDecoder,
+        args: &'s DecodeArgs,
+        token_count: usize,
+    ) -> Self {
+        Self {
+            seqs,
+            decoder,
+            args,
+            token_count,
+            n_generated: 0,
+            accepted: VecDeque::new(),
+            stats: Vec::new(),
+            excluded: Vec::new(),
+            message_end: None,
+            finished: false,
+        }
+    }
+
+    /// Returns whether the message is on track to be hidden within the token limit.
+    fn on_schedule(&self) -> bool {
+        if self.decoder.is_done() || self.n_generated <= self.args.skip_start {
+            return true;
+        }
+
+        let bits_per_token =
+            self.decoder.bits_read() as f64 / (self.n_generated - self.args.skip_start) as f64;
+        let tokens_left = self.token_count - self.n_generated;
+
+        self.decoder.bits_remaining() as f64 <= bits_per_token * tokens_left as f64
+    }
+
+    /// Returns the position of the first token of the text so far that would be tokenized
+    /// differently, if any. The end of generation token is not part of the text, so it is never
+    /// the one returned.
+    fn first_unstable(&self) -> Result<Option<usize>> {
+        let model = self.seqs.model();
+        let mut text = text_tokens(&*self.seqs);
+        if let Some((&last, rest)) = text.split_last() {
+            if model.is_eog_token(last) {
+                text = rest;
+            }
+        }
+
+        let mut bytes = Vec::new();
+        for &token in text {
+            bytes.extend(model.token_to_bytes(token)?);
+        }
+        // When the text tokenizes into more tokens, only the last of them can differ.
+        Ok(
+            retokenization_mismatch(model, &String::from_utf8_lossy(&bytes), text)?
+                .map(|position| position.min(text.len().saturating_sub(1))),
+        )
+    }
+
+    /// Forgets the tokens excluded at or after `start`. They were excluded from text that the next
+    /// attempt will not generate, since it takes a different detour, so they would only constrain
+    /// it for no reason.
+    fn drop_window_exclusions(&mut self, start: usize) {
+        self.excluded.retain(|&(position, _)| position < start);
+    }
+
+    /// Restores the state from the start of the window, to generate it again.
+    fn rewind(&mut self, start: &WindowStart<S::Snapshot>) -> Result<()> {
+        self.seqs.restore(&start.snapshot)?;
+        self.decoder = start.decoder.clone();
+        self.n_generated = start.n_generated;
+        self.message_end = start.message_end;
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Generates up to [`BACKTRACK_WINDOW`] tokens, with the writer choosing its `detour`-th most
+    /// likely token the first time that it chooses one.
+    fn sample_window(&mut self, mut detour: usize) -> Result<Vec<TokenStats>> {
+        let mut window = Vec::new();
+
+        while window.len() < BACKTRACK_WINDOW && self.n_generated < self.token_count {
+            let excluded = self
+                .excluded
+                .iter()
+                .filter(|&&(position, _)| position == self.n_generated)
+                .map(|&(_, token)| token)
+                .collect::<Vec<_>>();
+            let stats = sample_steganography(
+                &mut *self.seqs,
+                &mut self.decoder,
+                self.args,
+                &mut detour,
+                &excluded,
+            )?;
+            let token = stats.token();
+            window.push(stats);
+            self.n_generated += 1;
+            if self.message_end.is_none() && self.decoder.is_done() {
+                self.message_end = Some(self.n_generated);
+            }
+
+            if self.seqs.model().is_eog_token(token) {
+                self.finished = true;
+                break;
+            }
+        }
+
+        if window.is_empty() {
+            self.finished = true;
+        }
+        Ok(window)
+    }
+
+    fn generate_window(&mut self) -> Result<()> {
+        let start = WindowStart {
+            snapshot: self.seqs.snapshot()?,
+            decoder: self.decoder.clone(),
+            n_generated: self.n_generated,
+            message_end: self.message_end,
+        };
+        let start_generated = self.n_generated;
+        // The attempt that hid the most bits while only falling behind, with the bits it hid, and
+        // the tokens that were excluded when it was generated.
+        let mut best: Option<(usize, usize)> = None;
+        let mut best_excluded = Vec::new();
+        let mut error = None;
+
+        for attempt in 0..=MAX_RETRIES {
+            if attempt > 0 {
+                self.rewind(&start)?;
+            }
+            let window = self.sample_window(attempt)?;
+
+            // Tokens after the end of the message can only change the bits after it, which the
+            // decoder ignores.
+            let hiding = self.message_end.map_or(window.len(), |end| {
+                end.saturating_sub(start_generated).min(window.len())
+            });
+            let fragile = window[..hiding].iter().position(|stats| stats.fragile);
+            // Tokens before the window have already been yielded, so they cannot be changed.
+            let unstable = match window.len() {
+                0 => None,
+                n => self
+                    .first_unstable()?
+                    .map(|position| position.clamp(start_generated, start_generated + n - 1)),
+            };
+
+            if let Some(position) = unstable {
+                self.excluded
+                    .push((position, window[position - start_generated].token()));
+                debug!(
+                    "Token {position} would be tokenized differently, retrying (attempt {})",
+                    attempt + 1
+                );
+                error = Some(Error::RetokenizationMismatch(position));
+            } else if let Some(i) = fragile {
+                debug!(
+                    "Token {} is fragile, retrying (attempt {})",
+                    start_generated + i,
+                    attempt + 1
+                );
+                error = Some(Error::FragileToken(start_generated + i));
+                self.drop_window_exclusions(start_generated);
+            } else if !self.finished && !self.on_schedule() {
+                debug!(
+                    "Encoding fell behind after {} tokens, retrying (attempt {})",
+                    self.n_generated,
+                    attempt + 1
+                );
+                let bits = self.decoder.bits_read();
+                if best.is_none_or(|(best_bits, _)| bits > best_bits) {
+                    best = Some((bits, attempt));
+                    best_excluded.clone_from(&self.excluded);
+                }
+                self.drop_window_exclusions(start_generated);
+            } else {
+                self.accepted.extend(window);
+                return Ok(());
+            }
+        }
+
+        // Falling behind does not mean that the message will not fit, since later windows may
+        // hide more of it, so the attempt that hid the most is generated again and kept.
+        if let Some((_, attempt)) = best {
+            debug!(
+                "Encoding is still behind after {MAX_RETRIES} retries, continuing from attempt {}",
+                attempt + 1
+            );
+            self.rewind(&start)?;
+            self.excluded = best_excluded;
+            let window = self.sample_window(attempt)?;
+            self.accepted.extend(window);
+            return Ok(());
+        }
+
+        // Text that would fail these checks could not be decoded, so stop generating it now.
+        Err(error.expect("every attempt failed a check").into())
+    }
 }
 
+/// The state of an [`Encoder`] at the start of a window.
+struct WindowStart<T> {
+    snapshot: T,
+    decoder: RangeDecoder,
+    n_generated: usize,
+    message_end: Option<usize>,
+}
+
+impl<S: Sequences> Iterator for Encoder<'_, S> {
+    type Item = Result<LlamaToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(stats) = self.accepted.pop_front() {
+                let token = stats.token();
+                // The end of generation token is not part of the text.
+                if !self.seqs.model().is_eog_token(token) {
+                    self.stats.push(stats);
+                }
+                return Some(Ok(token));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(e) = self.generate_window() {
+                self.finished = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Recovers the bits hidden in `tokens`, given the steganographer's and auxiliary model's
+/// predictions before each of them. The statistics of each token are pushed to `stats` as it is
+/// decoded, including the token that decoding stopped at, if any.
+///
+/// With error correction enabled, tokens that were filtered out are passed on to [`fec::decode`]
+/// as lost instead of failing decoding. Otherwise, decoding stops at a token that was filtered out,
+/// since it may come after the end of the message: whitespace may have been added to the end of
+/// the text, and in robust mode, the tokens after the message may be decoded differently on other
+/// machines. [`Steganography::decode_bools`] fails if the message is incomplete.
 pub fn recover_message(
+    model: impl Vocabulary,
     steg_datas: Vec<LlamaTokenDataArray>,
     aux_datas: Vec<LlamaTokenDataArray>,
     tokens: &[LlamaToken],
-    _args: &DecodeArgs,
+    args: &DecodeArgs,
+    stats: &mut Vec<TokenStats>,
 ) -> Result<Vec<bool>> {
     let mut encoder = RangeEncoder::new();
+    let mut symbols = Vec::new();
 
-    for ((mut steg_data, mut aux_data), token) in steg_datas.into_iter().zip(aux_datas).zip(tokens)
+    for (i, ((mut steg_data, mut aux_data), &token)) in steg_datas
+        .into_iter()
+        .zip(aux_datas)
+        .zip(tokens)
+        .enumerate()
     {
-        let distinguishability = distinguishability(&mut steg_data, &mut aux_data);
-
-        if distinguishability > THRESHOLD {
+        let distinguishability = distinguishability(&mut steg_data, &mut aux_data, args.metric);
+        let fragile = args.robust
+            && i >= args.skip_start
+            && near_cutoff(&steg_data, distinguishability, args);
+
+        if i < args.skip_start || distinguishability > args.threshold {
+            stats.push(TokenStats {
+                fragile,
+                ..TokenStats::unembedded(token, distinguishability, None)
+            });
+            continue;
+        }
+        if !coding_candidates(&mut steg_data, &model, &tokens[..i], args) {
+            stats.push(TokenStats {
+                fragile,
+                ..TokenStats::unembedded(token, distinguishability, Some(0))
+            });
             continue;
         }
 
-        steg_data.apply_sampler(&LlamaSampler::min_p(0.01, 1));
-        let (table, denominator) = to_prob_table(&steg_data.data);
-        let token_i = steg_data
-            .data
-            .iter()
-            .position(|t| t.id() == *token)
-            .context("Token was filtered out")?;
+        let (table, denominator) = coding_prob_table(&steg_data.data, args);
+        let token_i = steg_data.data.iter().position(|t| t.id() == token);
+        let interval = token_i.map(|token_i| Interval::new(&table, denominator, token_i));
+
+        stats.push(TokenStats {
+            token: token.0,
+            metric: distinguishability,
+            embedded: true,
+            bits: interval.map_or(0., |interval| interval.bits()),
+            candidates: Some(steg_data.data.len()),
+            index: token_i,
+            interval,
+            fragile: fragile || (args.robust && near_quantization_step(&steg_data.data, args)),
+        });
+
+        if args.fec > 0 {
+            symbols.push(interval);
+            continue;
+        }
+        let Some(token_i) = token_i else {
+            break;
+        };
         encoder.encode(&table, denominator, token_i);
     }
 
+    if args.fec > 0 {
+        return fec::decode(&symbols);
+    }
     Ok(encoder.flush())
 }
 
+/// Makes room for more tokens once the context is full, by restarting it from the last half of
+/// its tokens. Compression and decompression both call this before predicting each token, so they
+/// see the same contexts no matter how long the message is.
+fn slide_window(gen: &mut impl LanguageModel) -> Result<()> {
+    let context_size = gen.context_size();
+    let n_tokens = gen.tokens().len();
+
+    // A context that only holds the BOS token has no room to slide forward.
+    ensure!(
+        context_size >= 2,
+        "the context must hold at least 2 tokens, but it holds {context_size}"
+    );
+    if n_tokens < context_size {
+        return Ok(());
+    }
+
+    let mut tokens = vec![gen.model().token_bos()];
+    tokens.extend_from_slice(&gen.tokens()[n_tokens - (context_size - 1) / 2..]);
+    gen.set_tokens(&tokens)
+}
+
 pub fn sample_decompress(
-    gen: &mut GenerationContext,
+    gen: &mut impl LanguageModel,
     decoder: &mut RangeDecoder,
 ) -> Result<LlamaToken> {
     if decoder.is_done() {
@@ -255,6 +765,8 @@ pub fn sample_decompress(
         return Ok(gen.model().token_eos());
     }
 
+    slide_window(gen)?;
+
     let mut data_array = gen.get_token_data();
     softmax_no_sort(&mut data_array);
 
@@ -284,121 +796,949 @@ pub fn compress(token_data: Vec<LlamaTokenDataArray>, tokens: &[LlamaToken]) ->
     Ok(encoder.flush())
 }
 
-pub fn message_to_bools(mut message: Vec<u8>) -> Vec<bool> {
-    let length = message.len() as u32;
-    message.splice(0..0, length.to_le_bytes());
+/// Adds a header to arbitrary bytes, compressing them with DEFLATE if that makes them shorter.
+pub fn bytes_payload(message: Vec<u8>) -> Vec<bool> {
+    let deflated = miniz_oxide::deflate::compress_to_vec(&message, 10);
+    let (kind, message) = if deflated.len() < message.len() {
+        (PayloadKind::Deflated, deflated)
+    } else {
+        (PayloadKind::Raw, message)
+    };
+    debug!("Hiding {} bits as {kind:?}", message.len() * 8);
 
-    message
-        .iter()
-        .flat_map(|b| (0..8).map(move |i| b & (1 << i) != 0))
-        .collect()
+    pack(kind, &bytes_to_bools(&message, None))
 }
 
-pub fn message_from_bools(bools: &[bool]) -> Vec<u8> {
-    let mut out: Vec<u8> = bools
-        .chunks_exact(8)
-        .map(|c| {
-            c.iter()
-                .enumerate()
-                .fold(0, |a, (i, b)| a | ((*b as u8) << i))
-        })
-        .collect();
+/// The result of hiding a message, along with statistics about how it was hidden.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct EncodeReport {
+    /// The generated cover text.
+    pub text: String,
+    /// The size of the message before it was compressed.
+    pub message_bits: usize,
+    /// The size of the message after compression, including the header.
+    pub payload_bits: usize,
+    /// The number of bits hidden in the text, including any encryption and error correction
+    /// overhead.
+    pub hidden_bits: usize,
+    /// The statistics of each token of the text.
+    pub tokens: Vec<TokenStats>,
+}
+
+impl EncodeReport {
+    /// The size of the payload relative to the original message.
+    pub fn compression_ratio(&self) -> f64 {
+        self.payload_bits as f64 / self.message_bits.max(1) as f64
+    }
 
-    let length = u32::from_le_bytes(out[0..4].try_into().unwrap());
-    out.drain(0..4);
-    out.truncate(length as usize);
+    /// The mean number of bits hidden per token of the text.
+    pub fn bits_per_token(&self) -> f64 {
+        self.hidden_bits as f64 / self.tokens.len().max(1) as f64
+    }
 
-    out
+    /// Returns the report as a JSON object, including the derived statistics.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::to_value(self).expect("Reports should always serialize");
+        json["token_count"] = self.tokens.len().into();
+        json["embedding_tokens"] = self.tokens.iter().filter(|t| t.embedded).count().into();
+        json["compression_ratio"] = self.compression_ratio().into();
+        json["bits_per_token"] = self.bits_per_token().into();
+        json
+    }
 }
 
-impl GenerationContext<'_> {
-    fn encode_bools(&mut self, bools: Vec<bool>, args: &EncodeArgs) -> Result<String> {
-        let mut steganographer = self.partial_clone()?;
-        let mut auxilliary = self.partial_clone()?;
-        let mut decoder = RangeDecoder::new(bools);
+/// Steganography and compression using any [`LanguageModel`].
+pub trait Steganography: LanguageModel + Sized {
+    /// Creates the sequences used to hide a message, with their prompts set and room for
+    /// `n_tokens` more tokens, up to the size of this context.
+    fn encoding_sequences(&self, args: &EncodeArgs, n_tokens: usize) -> Result<Self::Sequences> {
+        let mut prompts = vec![Vec::new(); N_SEQUENCES];
+        prompts[WRITER] = self.tokenize(&self.model().apply_chat_template(&args.prompt)?)?;
+        prompts[STEGANOGRAPHER] = self.tokenize("")?;
+        prompts[AUXILIARY] = self.tokenize(&aux_prompt(self.model(), &args.as_decode_args())?)?;
+
+        let mut seqs = self.prompted_sequences(&prompts, n_tokens)?;
+        match &args.prompt_cache {
+            Some(path) => set_tokens_cached(&mut seqs, &prompts, path)?,
+            None => seqs.set_all_tokens(&prompts)?,
+        }
+
+        Ok(seqs)
+    }
 
-        auxilliary.set_prompt(AUX_PROMPT)?;
-
-        let prompt = self.model().apply_chat_template(
-            &self.model().chat_template(None)?,
-            &[LlamaChatMessage::new(
-                "user".to_string(),
-                args.prompt.clone(),
-            )?],
-            true,
-        )?;
-        self.set_prompt(&prompt)?;
-
-        let out = generate_text(
-            self.model_longlived(),
-            true,
-            (0..args.token_count).map(|_| {
-                sample_steganography(
-                    &mut steganographer,
-                    &mut auxilliary,
-                    self,
-                    &mut decoder,
-                    args,
-                )
-            }),
-        )?;
+    /// Creates sequences laid out like those of [`Steganography::encoding_sequences`], so that the
+    /// cover text is evaluated in the same batches as when it was generated. The writer's prompt
+    /// is unknown, so the writer's sequence only holds the BOS token.
+    fn decoding_sequences(&self, args: &DecodeArgs, n_tokens: usize) -> Result<Self::Sequences> {
+        let mut prompts = vec![self.tokenize("")?; N_SEQUENCES];
+        prompts[AUXILIARY] = self.tokenize(&aux_prompt(self.model(), args)?)?;
 
-        if !decoder.is_done() {
-            bail!("Could not encode entire message!");
+        let mut seqs = self.prompted_sequences(&prompts, n_tokens)?;
+        seqs.set_all_tokens(&prompts)?;
+        Ok(seqs)
+    }
+
+    /// Creates sequences with room for `prompts` and `n_tokens` more tokens, up to the size of
+    /// this context.
+    fn prompted_sequences(
+        &self,
+        prompts: &[Vec<LlamaToken>],
+        n_tokens: usize,
+    ) -> Result<Self::Sequences> {
+        let longest_prompt = prompts.iter().map(Vec::len).max().unwrap_or(0);
+        let n_ctx = (longest_prompt + n_tokens).min(self.context_size());
+        self.sequences(prompts.len(), n_ctx)
+    }
+
+    /// Hides `bools` in text generated from `args.prompt`, writing the text to `out` as it is
+    /// generated.
+    fn encode_bools(
+        &mut self,
+        bools: Vec<bool>,
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<String> {
+        Ok(self.encode_bools_report(bools, args, out)?.text)
+    }
+
+    /// Like [`Steganography::encode_bools`], but also returns statistics about how the bits were
+    /// hidden. The message is taken to be `bools` itself.
+    fn encode_bools_report(
+        &mut self,
+        bools: Vec<bool>,
+        args: &EncodeArgs,
+        out: &mut impl Write,
+    ) -> Result<EncodeReport> {
+        let payload_bits = bools.len();
+        let bools = match args.key.secret()? {
+            Some(secret) => encrypt_bools(&secret, &bools)?,
+            None => bools,
+        };
+        let segments = if args.fec > 0 {
+            fec::encode(&bools, args.fec)?
+        } else {
+            vec![bools]
+        };
+
+        let mut seqs = self.encoding_sequences(args, args.token_count)?;
+        let decode_args = args.as_decode_args();
+
+        let hidden_bits = segments.iter().map(Vec::len).sum();
+
+        let mut encoder = Encoder::new(
+            &mut seqs,
+            RangeDecoder::segmented(segments),
+            &decode_args,
+            args.token_count,
+        );
+        let text = generate_text(self.model(), out, &mut encoder)?;
+
+        if let Some(path) = &args.trace {
+            write_trace(path, &encoder.stats)?;
+        }
+        if !encoder.decoder.is_done() {
+            return Err(Error::MessageTooLong.into());
+        }
+        // The decoder only sees the normalized text, so it must tokenize into the tokens it was
+        // generated as, at least until the whole message has been hidden. Normalizing can still
+        // remove whitespace at the end of the text, which the decoder ignores.
+        let tokens = encoder
+            .stats
+            .iter()
+            .map(TokenStats::token)
+            .collect::<Vec<_>>();
+        let message_end = encoder.message_end.unwrap_or(tokens.len());
+        if let Some(position) = retokenization_mismatch(self.model(), &normalize(&text), &tokens)?
+            .filter(|&position| position < message_end)
+        {
+            return Err(Error::RetokenizationMismatch(position).into());
+        }
+
+        Ok(EncodeReport {
+            text,
+            message_bits: payload_bits,
+            payload_bits,
+            hidden_bits,
+            tokens: encoder.stats,
+        })
+    }
+
+    /// Estimates the number of tokens needed to hide `n_bits` bits, by generating `sample_tokens`
+    /// tokens that hide random bits.
+    fn estimate_bools(
+        &mut self,
+        n_bits: usize,
+        args: &EncodeArgs,
+        sample_tokens: usize,
+    ) -> Result<Estimate> {
+        let n_bits = match args.key.secret()? {
+            Some(_) => (n_bits.div_ceil(8) + crypto::OVERHEAD) * 8,
+            None => n_bits,
+        };
+        let n_bits = if args.fec > 0 {
+            fec::encoded_bits(n_bits, args.fec)
+        } else {
+            n_bits
+        };
+
+        let mut seqs = self.encoding_sequences(args, args.skip_start + sample_tokens)?;
+        let decode_args = args.as_decode_args();
+
+        // Hidden messages are indistinguishable from random bits, so they are hidden at the same
+        // rate. No token hides more than 32 bits, so the random bits never run out.
+        let bits = (0..sample_tokens * 32 + 64)
+            .map(|_| rand::random())
+            .collect();
+        let mut decoder = RangeDecoder::new(bits);
+
+        let mut samples = Vec::with_capacity(sample_tokens);
+        for i in 0..args.skip_start + sample_tokens {
+            let stats = sample_steganography(&mut seqs, &mut decoder, &decode_args, &mut 0, &[])?;
+
+            if i >= args.skip_start {
+                samples.push(stats.bits);
+            }
         }
 
-        Ok(out)
+        Ok(Estimate::new(n_bits, args.skip_start, &samples))
     }
 
-    pub fn encode_message(&mut self, message: Vec<u8>, args: &EncodeArgs) -> Result<String> {
-        self.encode_bools(message_to_bools(message), args)
+    /// Estimates the number of tokens needed to hide `message` with
+    /// [`Steganography::encode_message`].
+    fn estimate_message(
+        &mut self,
+        message: Vec<u8>,
+        args: &EncodeArgs,
+        sample_tokens: usize,
+    ) -> Result<Estimate> {
+        self.estimate_bools(bytes_payload(message).len(), args, sample_tokens)
     }
 
-    pub fn encode_compressed(&mut self, message: &str, args: &EncodeArgs) -> Result<String> {
+    /// Estimates the number of tokens needed to hide `message` with
+    /// [`Steganography::encode_compressed`].
+    fn estimate_compressed(
+        &mut self,
+        message: &str,
+        args: &EncodeArgs,
+        sample_tokens: usize,
+    ) -> Result<Estimate> {
+        let n_bits = self.compressed_payload(message)?.len();
+        self.estimate_bools(n_bits, args, sample_tokens)
+    }
+
+    /// Hides arbitrary bytes, compressing them with DEFLATE if that makes them shorter.
+    fn encode_message(&mut self, message: Vec<u8>, args: &EncodeArgs) -> Result<String> {
+        self.encode_bools(bytes_payload(message), args, &mut std::io::sink())
+    }
+
+    /// Compresses `message` with the model and adds its CRC-32 and a header to it.
+    fn compressed_payload(&mut self, message: &str) -> Result<Vec<bool>> {
         let bools = self.compress_message(message)?;
-        eprintln!("COMPRESSION: {} {}", message.len() * 8, bools.len());
-        self.encode_bools(bools, args)
+        debug!(
+            "Compressed {} bits of text to {} bits",
+            message.len() * 8,
+            bools.len()
+        );
+
+        let mut payload = bytes_to_bools(&crc32fast::hash(message.as_bytes()).to_le_bytes(), None);
+        payload.extend(bools);
+        Ok(pack(PayloadKind::Compressed, &payload))
     }
 
-    pub fn decode_bools(&mut self, text: &str, args: &DecodeArgs) -> Result<Vec<bool>> {
-        self.clear()?;
-        let tokens = self.model().str_to_token(text, AddBos::Never)?;
-        let data = self.add_tokens_get_token_data(&tokens)?;
-        self.set_prompt(AUX_PROMPT)?;
-        let aux_data = self.add_tokens_get_token_data(&tokens)?;
+    fn encode_compressed(&mut self, message: &str, args: &EncodeArgs) -> Result<String> {
+        let bools = self.compressed_payload(message)?;
+        self.encode_bools(bools, args, &mut std::io::sink())
+    }
+
+    /// Recovers the encrypted bits hidden in `text`, after undoing common edits to it with
+    /// [`normalize`], pushing the statistics of each token to `stats` as it is decoded.
+    ///
+    /// The range coder needs exactly the logits that the encoder saw, so the text is evaluated one
+    /// token at a time in [`Steganography::decoding_sequences`], as it was when it was generated.
+    fn recover_bools(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        stats: &mut Vec<TokenStats>,
+    ) -> Result<Vec<bool>> {
+        let tokens = self.model().str_to_token(&normalize(text))?;
+        let mut seqs = self.decoding_sequences(args, tokens.len())?;
+
+        let mut data = Vec::with_capacity(tokens.len());
+        let mut aux_data = Vec::with_capacity(tokens.len());
+        for &token in &tokens {
+            data.push(seqs.get_token_data(STEGANOGRAPHER));
+            aux_data.push(seqs.get_token_data(AUXILIARY));
+            seqs.add_token(token)?;
+        }
 
-        recover_message(data, aux_data, &tokens, args)
+        recover_message(self.model(), data, aux_data, &tokens, args, stats)
     }
 
-    pub fn decode_messsage(&mut self, text: &str, args: &DecodeArgs) -> Result<Vec<u8>> {
-        Ok(message_from_bools(&self.decode_bools(text, args)?))
+    /// Decodes `text` and compares the decisions made for each token to those in a trace written
+    /// while encoding it. Returns the first token where they differ, if any.
+    fn diff_decode(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        encoded: &[TokenStats],
+    ) -> Result<Option<Divergence>> {
+        let mut stats = Vec::new();
+        // A token that was filtered out is a divergence, which is reported below.
+        if let Err(e) = self.recover_bools(text, args, &mut stats) {
+            if !matches!(e.downcast_ref(), Some(Error::TokenFilteredOut)) {
+                return Err(e);
+            }
+        }
+        if let Some(path) = &args.trace {
+            write_trace(path, &stats)?;
+        }
+
+        Ok(first_divergence(encoded, &stats))
+    }
+
+    fn decode_bools(&mut self, text: &str, args: &DecodeArgs) -> Result<Vec<bool>> {
+        let mut stats = Vec::new();
+        let bools = self.recover_bools(text, args, &mut stats);
+        if let Some(path) = &args.trace {
+            write_trace(path, &stats)?;
+        }
+        let bools = bools?;
+
+        let bools = match args.key.secret()? {
+            Some(secret) => decrypt_bools(&secret, &bools),
+            None => Ok(bools),
+        };
+        // Decoding stops at a token that was filtered out, which is only an error if the message
+        // had not ended before it.
+        let filtered_out = args.fec == 0
+            && stats
+                .last()
+                .is_some_and(|stats| stats.embedded && stats.index.is_none());
+        if filtered_out && !bools.as_ref().is_ok_and(|bools| unpack(bools).is_ok()) {
+            return Err(Error::TokenFilteredOut.into());
+        }
+        bools
+    }
+
+    /// Recovers a hidden message, decompressing it if necessary.
+    /// Recovers the message hidden in `text`. If it is compressed text, each piece of it is also
+    /// written to `out` as soon as it is decompressed.
+    fn decode_payload(
+        &mut self,
+        text: &str,
+        args: &DecodeArgs,
+        out: &mut impl Write,
+    ) -> Result<Payload> {
+        let (kind, payload) = unpack(&self.decode_bools(text, args)?)?;
+
+        match kind {
+            PayloadKind::Raw => Ok(Payload::Bytes(bools_to_bytes(&payload))),
+            PayloadKind::Compressed => Ok(Payload::Text(self.decompress_payload(payload, out)?)),
+            PayloadKind::Deflated => {
+                miniz_oxide::inflate::decompress_to_vec(&bools_to_bytes(&payload))
+                    .map(Payload::Bytes)
+                    .map_err(|e| {
+                        Error::InvalidMessage(format!("could not inflate message: {e}")).into()
+                    })
+            }
+        }
+    }
+
+    fn decode_messsage(&mut self, text: &str, args: &DecodeArgs) -> Result<Vec<u8>> {
+        Ok(self
+            .decode_payload(text, args, &mut std::io::sink())?
+            .into_bytes())
     }
 
-    pub fn decompress_message(&mut self, bools: Vec<bool>) -> Result<String> {
+    /// Decompresses a message compressed with [`Steganography::compress_message`], writing each
+    /// piece of it to `out` as soon as it is decompressed. Decompression stops at the EOS token
+    /// that ends every compressed message, so there is no length limit.
+    fn decompress_message(&mut self, bools: Vec<bool>, out: &mut impl Write) -> Result<String> {
         let mut decoder = RangeDecoder::new(bools);
 
         self.clear()?;
 
         generate_text(
-            self.model_longlived(),
-            true,
-            (0..1024).map(|_| sample_decompress(self, &mut decoder)),
+            self.model(),
+            out,
+            std::iter::repeat_with(|| sample_decompress(self, &mut decoder)),
         )
     }
 
-    pub fn decode_compressed(&mut self, text: &str, args: &DecodeArgs) -> Result<String> {
-        let bools = self.decode_bools(text, args)?;
-        self.decompress_message(bools)
+    /// Decompresses the payload of a [`PayloadKind::Compressed`] message, checking the text
+    /// against the CRC-32 at its start once all of it has been written to `out`.
+    fn decompress_payload(&mut self, payload: Vec<bool>, out: &mut impl Write) -> Result<String> {
+        if payload.len() < TEXT_CRC_BITS {
+            return Err(Error::InvalidMessage(
+                "compressed message is too short to contain a checksum".into(),
+            )
+            .into());
+        }
+        let crc = bools_to_bytes(&payload[..TEXT_CRC_BITS]);
+        let text = self.decompress_message(payload[TEXT_CRC_BITS..].to_vec(), out)?;
+
+        if crc32fast::hash(text.as_bytes()).to_le_bytes()[..] != crc {
+            return Err(Error::InvalidMessage(
+                "the decompressed text does not match its checksum. The model or settings may \
+                 differ from those used to encode it"
+                    .into(),
+            )
+            .into());
+        }
+        Ok(text)
+    }
+
+    fn decode_compressed(&mut self, text: &str, args: &DecodeArgs) -> Result<String> {
+        match self.decode_payload(text, args, &mut std::io::sink())? {
+            Payload::Text(text) => Ok(text),
+            Payload::Bytes(_) => Err(Error::InvalidMessage(
+                "expected compressed text, but the message contains raw bytes".into(),
+            )
+            .into()),
+        }
     }
 
-    pub fn compress_message(&mut self, message: &str) -> Result<Vec<bool>> {
+    /// Compresses `message` using the model's predictions. Messages longer than the context are
+    /// compressed in chunks, sliding the context forward whenever it fills up.
+    fn compress_message(&mut self, message: &str) -> Result<Vec<bool>> {
         self.clear()?;
 
-        let mut tokens = self.model().str_to_token(message, AddBos::Never)?;
+        let mut tokens = self.model().str_to_token(message)?;
         tokens.push(self.model().token_eos());
 
-        let data = self.add_tokens_get_token_data(&tokens)?;
+        let mut data = Vec::with_capacity(tokens.len());
+        let mut rest = tokens.as_slice();
+
+        while !rest.is_empty() {
+            slide_window(self)?;
+
+            let room = self.context_size() - self.tokens().len();
+            let (chunk, tail) = rest.split_at(room.min(rest.len()));
+
+            // The predictions after the last token of the chunk belong to the next chunk, which
+            // will start from a different context.
+            let mut chunk_data = self.add_tokens_get_token_data(chunk)?;
+            chunk_data.pop();
+            data.extend(chunk_data);
+            rest = tail;
+        }
 
         compress(data, &tokens)
     }
 }
+
+impl<M: LanguageModel> Steganography for M {}
+
+/// Returns a context evaluating `model`, and settings that leave room for a short message.
+#[cfg(test)]
+pub(crate) fn test_fixture(
+    model: &crate::mock::MockModel,
+) -> (crate::mock::MockContext<'_>, EncodeArgs) {
+    let mut args = EncodeArgs::new("Write a paragraph about range coding.");
+    args.token_count = 512;
+    (crate::mock::MockContext::new(model), args)
+}
+
+#[cfg(test)]
+fn test_compression_case(model: &crate::mock::MockModel, message: &str) {
+    use crate::mock::MockContext;
+
+    let mut gen = MockContext::new(model);
+
+    let bools = gen.compress_message(message).unwrap();
+    let mut out = Vec::new();
+    assert_eq!(gen.decompress_message(bools, &mut out).unwrap(), message);
+    assert_eq!(out, message.as_bytes());
+}
+
+#[test]
+fn test_compression() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0);
+
+    test_compression_case(&model, "Hello, World!");
+    test_compression_case(&model, "");
+    test_compression_case(&model, "A somewhat longer message.\nIt has two lines.");
+    test_compression_case(&MockModel::new(1).with_order(4), "Hello, World!");
+    test_compression_case(&model, &"Longer than 1024 tokens. ".repeat(100));
+}
+
+#[test]
+fn test_compression_chunked() {
+    use crate::mock::{MockContext, MockModel};
+
+    let model = MockModel::new(5).with_order(4);
+    let message = "A message that is much longer than the context. ".repeat(20);
+
+    for context_size in [2, 3, 64, 257] {
+        let mut gen = MockContext::new(&model).with_context_size(context_size);
+
+        let bools = gen.compress_message(&message).unwrap();
+        assert_eq!(
+            gen.decompress_message(bools, &mut std::io::sink()).unwrap(),
+            message
+        );
+        assert!(gen.tokens().len() <= context_size);
+    }
+
+    let mut gen = MockContext::new(&model).with_context_size(1);
+    assert!(gen.compress_message(&message).is_err());
+}
+
+#[test]
+fn test_steganography() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0);
+    let (mut gen, args) = test_fixture(&model);
+
+    let message = "This is a secret.";
+    let text = gen.encode_compressed(message, &args).unwrap();
+    assert_eq!(
+        gen.decode_compressed(&text, &args.as_decode_args())
+            .unwrap(),
+        message
+    );
+
+    let message = b"\x00\xffbinary\x80".to_vec();
+    let text = gen.encode_message(message.clone(), &args).unwrap();
+    assert_eq!(
+        gen.decode_payload(&text, &args.as_decode_args(), &mut std::io::sink())
+            .unwrap(),
+        Payload::Bytes(message)
+    );
+
+    let error = gen
+        .decode_compressed(&text, &args.as_decode_args())
+        .unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::InvalidMessage(_))));
+
+    let message = [b"\x89PNG\r\n".as_slice(), &[0; 256]].concat();
+    let text = gen.encode_message(message.clone(), &args).unwrap();
+    assert_eq!(
+        gen.decode_messsage(&text, &args.as_decode_args()).unwrap(),
+        message
+    );
+}
+
+#[test]
+fn test_steganography_settings() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(2);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.aux_prompt = "Only write about cats.".to_string();
+    args.raw_aux_prompt = true;
+
+    for (metric, threshold) in [(Metric::TotalVariation, 0.2), (Metric::JensenShannon, 0.1)] {
+        args.metric = metric;
+        args.threshold = threshold;
+
+        let message = "This is a secret.";
+        let text = gen.encode_compressed(message, &args).unwrap();
+        assert_eq!(
+            gen.decode_compressed(&text, &args.as_decode_args())
+                .unwrap(),
+            message
+        );
+    }
+}
+
+#[test]
+fn test_steganography_sampler_settings() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(3);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.skip_start = 16;
+    args.min_p = 0.05;
+    args.top_k = 20;
+    args.temp = 0.8;
+
+    let text1 = gen.encode_compressed("This is a secret.", &args).unwrap();
+    let text2 = gen.encode_compressed("Another secret.", &args).unwrap();
+
+    // The greedy prefix does not depend on the message.
+    assert_eq!(text1[..args.skip_start], text2[..args.skip_start]);
+    assert_ne!(text1, text2);
+
+    for (text, message) in [(text1, "This is a secret."), (text2, "Another secret.")] {
+        assert_eq!(
+            gen.decode_compressed(&text, &args.as_decode_args())
+                .unwrap(),
+            message
+        );
+    }
+}
+
+#[test]
+fn test_steganography_encrypted() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(4);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.key.passphrase = Some("hunter2".to_string());
+
+    let message = "This is a secret.";
+    let text = gen.encode_compressed(message, &args).unwrap();
+    let mut decode_args = args.as_decode_args();
+    assert_eq!(gen.decode_compressed(&text, &decode_args).unwrap(), message);
+
+    decode_args.key.passphrase = Some("hunter3".to_string());
+    let error = gen.decode_compressed(&text, &decode_args).unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::DecryptionFailed)));
+}
+
+#[test]
+fn test_steganography_suppresses_eos() {
+    use crate::mock::MockModel;
+
+    // EOS is the most likely token after every context.
+    let model = MockModel::new(6).with_eos_logit(6.5);
+    let (mut gen, args) = test_fixture(&model);
+
+    let message = "This is a secret.";
+    let text = gen.encode_compressed(message, &args).unwrap();
+    assert_eq!(
+        gen.decode_compressed(&text, &args.as_decode_args())
+            .unwrap(),
+        message
+    );
+}
+
+#[test]
+fn test_estimate_steganography() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0);
+    let (mut gen, mut args) = test_fixture(&model);
+
+    let message = "This is a secret.";
+    let estimate = gen.estimate_compressed(message, &args, 128).unwrap();
+    assert_eq!(estimate.sampled_tokens, 128);
+    assert!(estimate.bits_per_token > 0.);
+
+    assert!(estimate.expected_tokens() < args.token_count as f64);
+
+    args.key.passphrase = Some("hunter2".to_string());
+    let encrypted = gen.estimate_compressed(message, &args, 128).unwrap();
+    assert_eq!(
+        encrypted.message_bits,
+        (estimate.message_bits.div_ceil(8) + crypto::OVERHEAD) * 8
+    );
+}
+
+#[test]
+fn test_steganography_message_too_long() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.token_count = 4;
+
+    let error = gen
+        .encode_compressed("This is a secret.", &args)
+        .unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::MessageTooLong)));
+
+    // A message that falls behind is still hidden in as many tokens as are allowed, since later
+    // windows could catch up. The mock model's tokens are bytes.
+    args.token_count = 1024;
+    let mut out = Vec::new();
+    let error = gen
+        .encode_bools(vec![true; 1 << 16], &args, &mut out)
+        .unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::MessageTooLong)));
+    assert_eq!(out.len(), args.token_count);
+}
+
+#[test]
+fn test_encode_report() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0);
+    let (mut gen, args) = test_fixture(&model);
+
+    let bools = gen.compressed_payload("This is a secret.").unwrap();
+    let n_bits = bools.len();
+    let report = gen
+        .encode_bools_report(bools, &args, &mut std::io::sink())
+        .unwrap();
+
+    assert_eq!(report.payload_bits, n_bits);
+    assert_eq!(report.hidden_bits, n_bits);
+    assert!(report.tokens[..args.skip_start].iter().all(|t| !t.embedded));
+    assert!(report.tokens.iter().all(|t| t.embedded || t.bits == 0.));
+
+    assert!(report.tokens.iter().any(|t| t.bits > 0.));
+
+    let decoded = gen
+        .decode_bools(&report.text, &args.as_decode_args())
+        .unwrap();
+    assert!(decoded.starts_with(&gen.compressed_payload("This is a secret.").unwrap()));
+}
+
+#[test]
+fn test_robust_prob_table() {
+    let mut args = DecodeArgs {
+        robust: true,
+        ..Default::default()
+    };
+
+    let data = |ps: &[f32]| {
+        ps.iter()
+            .enumerate()
+            .map(|(i, &p)| LlamaTokenData::new(LlamaToken(i as i32), p.ln(), p))
+            .collect::<Vec<_>>()
+    };
+
+    // Far from a quantization step, small changes do not affect the table.
+    let ps = [0.45, 0.3, 0.17, 0.08];
+    assert!(!near_quantization_step(&data(&ps), &args));
+    let nudged = [0.45, 0.3 + 1e-5, 0.17, 0.08];
+    assert_eq!(
+        coding_prob_table(&data(&ps), &args),
+        coding_prob_table(&data(&nudged), &args)
+    );
+
+    // 0.25 is exactly 16 steps of 1/64.
+    let ps = [0.5, 0.25, 0.25];
+    assert!(near_quantization_step(&data(&ps), &args));
+
+    // Nearly tied candidates keep their places when their order flips.
+    let model = crate::mock::MockModel::new(0);
+    let candidates = |a: f32, b: f32, args: &DecodeArgs| {
+        let mut data = LlamaTokenDataArray::from_iter(
+            [
+                LlamaTokenData::new(LlamaToken(b'a' as i32), a, 0.),
+                LlamaTokenData::new(LlamaToken(b'b' as i32), b, 0.),
+            ],
+            false,
+        );
+        assert!(coding_candidates(&mut data, model, &[], args));
+        data.data.iter().map(LlamaTokenData::id).collect::<Vec<_>>()
+    };
+    assert_eq!(
+        candidates(1.0, 1.0 + 1e-6, &args),
+        candidates(1.0 + 1e-6, 1.0, &args)
+    );
+
+    args.robust = false;
+    assert_ne!(
+        coding_prob_table(&data(&[0.5, 0.3, 0.2]), &args),
+        coding_prob_table(&data(&[0.5, 0.3 + 1e-6, 0.2 - 1e-6]), &args)
+    );
+    assert_ne!(
+        candidates(1.0, 1.0 + 1e-6, &args),
+        candidates(1.0 + 1e-6, 1.0, &args)
+    );
+}
+
+#[test]
+fn test_robust_steganography() {
+    use crate::mock::{MockContext, MockModel};
+
+    let model = MockModel::new(0);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.token_count = 1024;
+    args.robust = true;
+    // The mock model's predictions are much flatter than a real model's, so many more of its
+    // tokens are fragile, and the steganographer chooses nearly all of them, so the writer can
+    // rarely steer around them. A margin this small keeps them rare.
+    args.robust_margin = 3e-5;
+
+    let message = "This is a secret.";
+    let text = gen.encode_compressed(message, &args).unwrap();
+    assert_eq!(
+        gen.decode_compressed(&text, &args.as_decode_args())
+            .unwrap(),
+        message
+    );
+
+    let mut decode_args = args.as_decode_args();
+    decode_args.robust = false;
+    assert!(gen.decode_compressed(&text, &decode_args).is_err());
+
+    // With a margin this large, every window has fragile tokens, so encoding fails instead of
+    // producing text that may not decode elsewhere.
+    let mut fragile_args = args.clone();
+    fragile_args.robust_margin = 0.1;
+    let error = gen.encode_compressed(message, &fragile_args).unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::FragileToken(_))));
+
+    // On a machine whose logits differ by less than the margin, only robust decoding recovers the
+    // hidden bytes. Text compressed with the model is not protected, so bytes are hidden instead.
+    let noisy_model = model.with_noise(1e-5);
+    let mut noisy = MockContext::new(&noisy_model);
+    let bytes = message.as_bytes().to_vec();
+    let text = gen.encode_message(bytes.clone(), &args).unwrap();
+    assert_eq!(
+        noisy
+            .decode_messsage(&text, &args.as_decode_args())
+            .unwrap(),
+        bytes
+    );
+
+    // Text is decompressed with the noisy model's own predictions, which its checksum catches.
+    let text = gen.encode_compressed(message, &args).unwrap();
+    let error = noisy
+        .decode_compressed(&text, &args.as_decode_args())
+        .unwrap_err();
+    assert!(matches!(error.downcast(), Ok(Error::InvalidMessage(_))));
+
+    args.robust = false;
+    let text = gen.encode_message(bytes.clone(), &args).unwrap();
+    assert_eq!(
+        gen.decode_messsage(&text, &args.as_decode_args()).unwrap(),
+        bytes
+    );
+    assert!(noisy
+        .decode_messsage(&text, &args.as_decode_args())
+        .is_err());
+}
+
+#[test]
+fn test_fec_steganography() {
+    use crate::mock::MockModel;
+
+    test_fec_case(&MockModel::new(0), false);
+    // An edit changes the predictions for the next 32 tokens of this model, rather than 2.
+    test_fec_case(&MockModel::new(0).with_order(32), true);
+}
+
+#[cfg(test)]
+fn test_fec_case(model: &crate::mock::MockModel, robust: bool) {
+    let (mut gen, mut args) = test_fixture(model);
+    args.token_count = 4096;
+    args.fec = 2;
+    args.robust = robust;
+    // As in `test_robust_steganography`, a small margin keeps the mock model's tokens from being
+    // fragile too often to avoid.
+    args.robust_margin = 1e-5;
+
+    let message = "This is a secret.";
+    let payload = gen.compressed_payload(message).unwrap();
+    let n_bits = payload.len();
+    let report = gen
+        .encode_bools_report(payload, &args, &mut std::io::sink())
+        .unwrap();
+    assert_eq!(report.hidden_bits, fec::encoded_bits(n_bits, 2));
+
+    // Edit the token that hides the middle of the hidden bits. The mock model's tokens are bytes.
+    let mut bits = 0.;
+    let position = report
+        .tokens
+        .iter()
+        .position(|stats| {
+            bits += stats.bits;
+            bits > report.hidden_bits as f64 / 2.
+        })
+        .unwrap();
+    let mut substituted = report.text.clone().into_bytes();
+    substituted[position] = if substituted[position] == b'e' {
+        b'a'
+    } else {
+        b'e'
+    };
+    let mut deleted = report.text.clone().into_bytes();
+    deleted.remove(position);
+
+    let decode_args = args.as_decode_args();
+    for text in [report.text.into_bytes(), substituted, deleted] {
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(gen.decode_compressed(&text, &decode_args).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_stable_steganography() {
+    use crate::mock::MockModel;
+
+    let model = MockModel::new(0).with_merges();
+    let (mut gen, args) = test_fixture(&model);
+
+    let message = "This is a secret.";
+    let payload = gen.compressed_payload(message).unwrap();
+    let report = gen
+        .encode_bools_report(payload, &args, &mut std::io::sink())
+        .unwrap();
+
+    // The text tokenizes into the tokens it was generated from, though the mock model would
+    // merge some of them if they were chosen freely.
+    let tokens = report
+        .tokens
+        .iter()
+        .map(TokenStats::token)
+        .collect::<Vec<_>>();
+    assert_eq!(model.str_to_token(&report.text).unwrap(), tokens);
+    assert_eq!(
+        normalize(&report.text),
+        report.text.trim_end_matches([' ', '\t'])
+    );
+
+    // Wrap the text at 40 columns, only breaking lines before letters, since lines starting with
+    // them continue the paragraph.
+    let mut reflowed = String::new();
+    let mut column = 0;
+    let mut chars = report.text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' && column >= 40 && chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            reflowed.push('\n');
+            column = 0;
+        } else {
+            reflowed.push(c);
+            column = if c == '\n' { 0 } else { column + 1 };
+        }
+    }
+    assert_ne!(reflowed, report.text);
+
+    let decode_args = args.as_decode_args();
+    for text in [
+        report.text.clone(),
+        report.text.replace('\n', "  \r\n"),
+        format!("{}\n\n", report.text.trim_end()),
+        reflowed,
+    ] {
+        assert_eq!(gen.decode_compressed(&text, &decode_args).unwrap(), message);
+    }
+}
+
+#[test]
+fn test_excluded_tokens() {
+    use crate::mock::{MockContext, MockModel};
+
+    let model = MockModel::new(0).with_merges();
+    let gen = MockContext::new(&model);
+    let args = EncodeArgs::new("Write a paragraph about range coding.");
+    let decode_args = args.as_decode_args();
+
+    // The first tokens are chosen by the writer, which avoids excluded tokens.
+    let sample = |excluded: &[LlamaToken]| {
+        let mut seqs = gen.encoding_sequences(&args, 1).unwrap();
+        let mut decoder = RangeDecoder::new(vec![true; 64]);
+        sample_steganography(&mut seqs, &mut decoder, &decode_args, &mut 0, excluded)
+            .unwrap()
+            .token()
+    };
+    let token = sample(&[]);
+    let other = sample(&[token]);
+    assert_ne!(other, token);
+    assert_eq!(sample(&[other]), token);
+
+    // Exclusions from an abandoned attempt at a window do not carry over to the next one.
+    let mut seqs = gen.encoding_sequences(&args, 64).unwrap();
+    let mut encoder = Encoder::new(
+        &mut seqs,
+        RangeDecoder::new(vec![true; 64]),
+        &decode_args,
+        64,
+    );
+    encoder.excluded = vec![(3, token), (32, token), (40, other)];
+    encoder.drop_window_exclusions(32);
+    assert_eq!(encoder.excluded, [(3, token)]);
+
+    let error = Error::RetokenizationMismatch(12);
+    assert!(error.to_string().contains("token 12"));
+}
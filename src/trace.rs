@@ -0,0 +1,305 @@
+//! Per-token traces of encoding and decoding.
+//!
+//! A trace is a JSON Lines file with one object per token of the cover text, holding its
+//! `position` along with its [`TokenStats`]. Encoding and decoding the same text with the same
+//! model and settings produce identical traces, so diffing the encoder's trace against the
+//! decoder's shows where the two first disagreed. [`first_divergence`] does this, and guesses
+//! why they disagreed.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::steganography::{Interval, TokenStats};
+
+/// Differences in the metric or intervals up to this size are attributed to numerical
+/// differences between machines rather than different models or settings.
+const DRIFT_TOLERANCE: f64 = 1e-3;
+
+/// A single line of a trace.
+#[derive(Serialize)]
+struct TraceEntry<'a> {
+    position: usize,
+    #[serde(flatten)]
+    stats: &'a TokenStats,
+}
+
+/// Writes the statistics of each token to a trace file at `path`.
+pub fn write_trace(path: &Path, stats: &[TokenStats]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    for (position, stats) in stats.iter().enumerate() {
+        serde_json::to_writer(&mut out, &TraceEntry { position, stats })?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(out.flush()?)
+}
+
+/// Reads a trace written by [`write_trace`].
+pub fn read_trace(path: &Path) -> Result<Vec<TokenStats>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The most likely reason that two traces diverged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cause {
+    /// The tokens themselves differ, so the cover text was modified, truncated, or tokenized
+    /// differently than it was generated.
+    Text,
+    /// The predictions differ by tiny amounts, as happens when the model runs on different
+    /// hardware or a different build of llama.cpp.
+    Drift,
+    /// The predictions or decisions differ in a way that tiny numerical differences cannot
+    /// explain, so the model or settings differ.
+    Settings,
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::Text => write!(f, "the cover text differs from the generated text"),
+            Cause::Drift => write!(
+                f,
+                "the model's predictions differ slightly, most likely because of different \
+                 hardware or llama.cpp builds"
+            ),
+            Cause::Settings => write!(f, "the model or settings differ from those used to encode"),
+        }
+    }
+}
+
+/// The first token where the decoder's decisions differ from the encoder's.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Divergence {
+    /// The position of the token in the cover text.
+    pub position: usize,
+    pub cause: Cause,
+    /// The encoder's statistics for the token, if the encoder generated it.
+    pub encoded: Option<TokenStats>,
+    /// The decoder's statistics for the token, if the decoder reached it.
+    pub decoded: Option<TokenStats>,
+    /// The difference between the encoder's and decoder's metric values at the token.
+    pub metric_difference: Option<f64>,
+    /// The largest difference between the bounds of the encoder's and decoder's intervals, as a
+    /// fraction of the whole range.
+    pub interval_difference: Option<f64>,
+    /// The largest difference between the metric values at any earlier token.
+    pub earlier_metric_difference: f64,
+}
+
+fn interval_difference(a: &Interval, b: &Interval) -> f64 {
+    let fraction = |x: u64, interval: &Interval| x as f64 / interval.denominator as f64;
+
+    (fraction(a.low, a) - fraction(b.low, b))
+        .abs()
+        .max((fraction(a.high, a) - fraction(b.high, b)).abs())
+}
+
+/// Returns whether the same decisions were made for a token.
+fn same_decisions(a: &TokenStats, b: &TokenStats) -> bool {
+    a.token == b.token
+        && a.embedded == b.embedded
+        && a.candidates == b.candidates
+        && a.index == b.index
+        && a.interval == b.interval
+}
+
+/// Compares an encoder's trace to a decoder's trace of the same text, and returns the first token
+/// where they made different decisions.
+pub fn first_divergence(encoded: &[TokenStats], decoded: &[TokenStats]) -> Option<Divergence> {
+    let mut earlier_metric_difference = 0f64;
+
+    for position in 0..encoded.len().max(decoded.len()) {
+        let (e, d) = (encoded.get(position), decoded.get(position));
+
+        let metric_difference = e.zip(d).map(|(e, d)| (e.metric - d.metric).abs());
+        let interval_difference = e
+            .and_then(|e| e.interval)
+            .zip(d.and_then(|d| d.interval))
+            .map(|(e, d)| interval_difference(&e, &d));
+
+        let cause = match (e, d) {
+            (Some(e), Some(d)) if same_decisions(e, d) => {
+                earlier_metric_difference =
+                    earlier_metric_difference.max(metric_difference.unwrap());
+                continue;
+            }
+            (Some(e), Some(d)) if e.token == d.token => {
+                let metric_difference = metric_difference.unwrap();
+                let small = metric_difference <= DRIFT_TOLERANCE
+                    && interval_difference.unwrap_or(0.) <= DRIFT_TOLERANCE;
+                // Identical predictions can only lead to different decisions under different
+                // settings.
+                let drifted = metric_difference.max(earlier_metric_difference) > 0.
+                    || interval_difference.unwrap_or(0.) > 0.;
+
+                if small && drifted {
+                    Cause::Drift
+                } else {
+                    Cause::Settings
+                }
+            }
+            _ => Cause::Text,
+        };
+
+        return Some(Divergence {
+            position,
+            cause,
+            encoded: e.cloned(),
+            decoded: d.cloned(),
+            metric_difference,
+            interval_difference,
+            earlier_metric_difference,
+        });
+    }
+
+    None
+}
+
+fn describe(f: &mut fmt::Formatter<'_>, side: &str, stats: Option<&TokenStats>) -> fmt::Result {
+    let Some(stats) = stats else {
+        return writeln!(f, "{side}: no token");
+    };
+
+    let embedded = if stats.embedded {
+        "embedded"
+    } else {
+        "not embedded"
+    };
+    write!(
+        f,
+        "{side}: token {}, metric {}, {embedded}",
+        stats.token, stats.metric
+    )?;
+    if let Some(candidates) = stats.candidates {
+        write!(f, ", {candidates} candidates")?;
+    }
+    match (stats.index, stats.interval) {
+        (Some(index), Some(interval)) => writeln!(
+            f,
+            ", index {index}, interval {}..{} of {}",
+            interval.low, interval.high, interval.denominator
+        ),
+        _ if stats.embedded => writeln!(f, ", not among the candidates"),
+        _ => writeln!(f),
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "The traces diverge at token {}: {}.",
+            self.position, self.cause
+        )?;
+        describe(f, "Encoder", self.encoded.as_ref())?;
+        describe(f, "Decoder", self.decoded.as_ref())?;
+
+        if let Some(difference) = self.metric_difference {
+            writeln!(f, "Metric difference: {difference:e}")?;
+        }
+        if let Some(difference) = self.interval_difference {
+            writeln!(f, "Interval difference: {difference:e}")?;
+        }
+        write!(
+            f,
+            "Largest metric difference before this token: {:e}",
+            self.earlier_metric_difference
+        )
+    }
+}
+
+#[test]
+fn test_trace() {
+    use crate::{
+        mock::MockModel,
+        steganography::{test_fixture, Steganography},
+    };
+
+    let dir = std::env::temp_dir();
+    let encode_path = dir.join(format!("encode-trace-{}", std::process::id()));
+    let decode_path = dir.join(format!("decode-trace-{}", std::process::id()));
+
+    let model = MockModel::new(0);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.trace = Some(encode_path.clone());
+
+    let text = gen.encode_compressed("This is a secret.", &args).unwrap();
+
+    let mut decode_args = args.as_decode_args();
+    decode_args.trace = Some(decode_path.clone());
+    gen.decode_compressed(&text, &decode_args).unwrap();
+
+    let encode_trace = std::fs::read_to_string(&encode_path).unwrap();
+    let decode_trace = std::fs::read_to_string(&decode_path).unwrap();
+    assert!(encode_trace
+        .lines()
+        .any(|line| line.contains(r#""embedded":true"#)));
+    assert_eq!(encode_trace, decode_trace);
+
+    // Decoding with different settings fails, but still writes the trace up to the failure.
+    decode_args.min_p = 0.5;
+    assert!(gen.decode_compressed(&text, &decode_args).is_err());
+    let decode_trace = std::fs::read_to_string(&decode_path).unwrap();
+    assert_ne!(encode_trace, decode_trace);
+
+    std::fs::remove_file(encode_path).unwrap();
+    std::fs::remove_file(decode_path).unwrap();
+}
+
+#[test]
+fn test_first_divergence() {
+    use crate::{
+        mock::MockModel,
+        steganography::{test_fixture, Steganography},
+    };
+
+    let path = std::env::temp_dir().join(format!("diff-trace-{}", std::process::id()));
+
+    let model = MockModel::new(0);
+    let (mut gen, mut args) = test_fixture(&model);
+    args.trace = Some(path.clone());
+
+    let text = gen.encode_compressed("This is a secret.", &args).unwrap();
+    let encoded = read_trace(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    let decode_args = args.as_decode_args();
+    assert_eq!(
+        gen.diff_decode(&text, &decode_args, &encoded).unwrap(),
+        None
+    );
+
+    let truncated = text
+        .chars()
+        .take(text.chars().count() / 2)
+        .collect::<String>();
+    let divergence = gen.diff_decode(&truncated, &decode_args, &encoded).unwrap();
+    assert_eq!(divergence.unwrap().cause, Cause::Text);
+
+    let mut other_args = decode_args.clone();
+    other_args.min_p = 0.5;
+    let divergence = gen.diff_decode(&text, &other_args, &encoded).unwrap();
+    assert_eq!(divergence.unwrap().cause, Cause::Settings);
+
+    // A decision flipped by a tiny change in the metric is attributed to drift.
+    let position = encoded.iter().position(|stats| stats.embedded).unwrap();
+    let mut drifted = encoded.clone();
+    drifted[position].metric += 1e-6;
+    drifted[position].embedded = false;
+    drifted[position].interval = None;
+    let divergence = first_divergence(&encoded, &drifted).unwrap();
+    assert_eq!(divergence.position, position);
+    assert_eq!(divergence.cause, Cause::Drift);
+}